@@ -1,7 +1,7 @@
 import axios from "axios";
 import { _ODK_FILE_LIST_FILENAME } from "../consts";
 
-import { AnalysisPackage, FolderType, CheckFileResponse, AnalysisSummary, DataFile, NewAnalysis, User, Topic, } from "../types";
+import { AnalysisPackage, DependencyProblem, SavedAnalysis, FolderType, CheckFileResponse, AnalysisSummary, DataFile, NewAnalysis, User, Topic, } from "../types";
 import { _HOST } from "../urls";
 
 // NEED PROPER ERROR HANDLING ON ALL OF THIS. Including try/catch
@@ -118,16 +118,31 @@ export async function deleteAnalysis(analysisId: string): Promise<AnalysisSummar
     }
 };
 
-export async function updateAnalysis(newAnalysis: AnalysisPackage): Promise<AnalysisPackage | undefined> {
+// Gives the dependency problems instead if the server refuses to save it because of them
+export async function updateAnalysis(newAnalysis: AnalysisPackage): Promise<SavedAnalysis | DependencyProblem[] | undefined> {
     try {
         const res = await axios.post(`${_HOST}/updateanalysis`, newAnalysis);
         return res.data;
     }
-    catch {
+    catch (err) {
+        if (axios.isAxiosError(err) && err.response?.status === 422) {
+            return err.response.data as DependencyProblem[];
+        }
         return undefined;
     }
 };
 
+// Runs keep going on the server when the page stops listening, so they have to be cancelled
+export async function cancelRun(runId: string): Promise<boolean> {
+    try {
+        await axios.post(`${_HOST}/runs/${runId}/cancel`);
+        return true;
+    }
+    catch {
+        return false;
+    }
+};
+
 ///////////////////////////////////////////////////////////////////////////////////
 ///////////////////////////////////////////////////////////////////////////////////
 ///////////////////////////////////////////////////////////////////////////////////
@@ -243,6 +243,8 @@ export function useAnalysis(refreshAnalyses: () => Promise<void>): UseAnalysis {
             id: apd.analysisPackage.id,
             code: tempCode,
             metadata: {
+                // Keeps settings this page doesn't edit (limits, ...)
+                ...apd.analysisPackage.metadata,
                 name: tempName,
                 language: tempLanguage,
                 inputs: tempInputs,
@@ -259,13 +261,20 @@ export function useAnalysis(refreshAnalyses: () => Promise<void>): UseAnalysis {
                 lastStatus: apd.analysisPackage.metadata.lastStatus,
             },
         };
-        const newAnalysisPackage = await updateAnalysis(apToSave);
-        if (!newAnalysisPackage) {
+        const saved = await updateAnalysis(apToSave);
+        if (!saved) {
             alert("Could not save");
             return;
         }
+        if (Array.isArray(saved)) {
+            alert(`Could not save:\n${saved.map(x => x.message).join("\n")}`);
+            return;
+        }
+        if (saved.problems.length > 0) {
+            alert(`Saved, but:\n${saved.problems.map(x => x.message).join("\n")}`);
+        }
         await refreshAnalyses();
-        storeNewAnalysisPackage(newAnalysisPackage);
+        storeNewAnalysisPackage(saved.analysisPackage);
     }
 
     function revert() {
@@ -11,7 +11,7 @@ export function useCFR(type: FolderType, analysisId: string, file: string, ignor
         const newCFR = await checkFile(folderType, analysisId, fileName);
         if (!newCFR) {
             console.error("Could not check file");
-            setCFR({ exists: false, date: "", size: 0, public: false })
+            setCFR({ exists: false, date: "", size: 0, public: false, version: null })
             return;
         }
         setCFR(newCFR);
@@ -3,6 +3,7 @@ import { NextPage } from "next";
 import { _HOST } from "../urls";
 import { LoginState, UseUser } from "../hooks/use_user";
 import { useAnalysis } from "../hooks/use_analysis";
+import { cancelRun } from "../actions/crud";
 import { UserBoundary } from "../components/user_boundary";
 import { UseAnalyses } from "../hooks/use_analyses";
 import {
@@ -52,11 +53,15 @@ const Index: NextPage<IndexProps> = (p) => {
   useEffect(() => {
     return () => {
       isAnalysingRef.current = false;
-      stop();
+      // Only stops listening, the run carries on
+      if (eventsRef.current) {
+        eventsRef.current.close();
+      }
     };
   }, []);
 
   const eventsRef = useRef<EventSource | undefined>(undefined);
+  const runIdRef = useRef<string | undefined>(undefined);
   const logAsStaticArrayRef = useRef<{ text: string; code: LogCode }[]>([
     { text: "Not yet run", code: LogCode.StatusUpdate },
   ]);
@@ -140,6 +145,7 @@ const Index: NextPage<IndexProps> = (p) => {
     setRightTab(RightTab.Log);
     updateLog();
 
+    runIdRef.current = undefined;
     eventsRef.current = new EventSource(
       `${_HOST}/run/${uap.data.analysisPackage.id}`,
       { withCredentials: true }
@@ -151,6 +157,9 @@ const Index: NextPage<IndexProps> = (p) => {
       switch (msgObj.msgType) {
         case MessageType.Heartbeat:
           return;
+        case MessageType.RunStarted:
+          runIdRef.current = msgObj.runId;
+          return;
         case MessageType.Stage:
           setAnalysisStatus((prev) => {
             const newStatus = { ...prev };
@@ -227,6 +236,8 @@ const Index: NextPage<IndexProps> = (p) => {
           });
           return;
         case MessageType.LogErr:
+        case MessageType.ScriptError:
+        case MessageType.ScriptWarning:
           logAsStaticArrayRef.current.push({
             text: msgObj.log,
             code: LogCode.Err,
@@ -273,6 +284,26 @@ const Index: NextPage<IndexProps> = (p) => {
             p.ua.refreshAnalyses();
           }
           return;
+        case MessageType.EndCancelled:
+          if (
+            eventsRef.current &&
+            eventsRef.current.readyState !== eventsRef.current.CLOSED
+          ) {
+            logAsStaticArrayRef.current.push({
+              text: "*** Closed analysis: CANCELLED ***",
+              code: LogCode.StatusUpdate,
+            });
+            isAnalysingRef.current = false;
+            setAnalysisStatus((prev) => {
+              const newStatus = { ...prev };
+              newStatus.finalStatus = Status.StoppedByUser;
+              newStatus.analyzing = false;
+              return newStatus;
+            });
+            eventsRef.current.close();
+            p.ua.refreshAnalyses();
+          }
+          return;
         default:
       }
     };
@@ -310,6 +341,9 @@ const Index: NextPage<IndexProps> = (p) => {
       eventsRef.current &&
       eventsRef.current.readyState !== eventsRef.current.CLOSED
     ) {
+      if (runIdRef.current) {
+        cancelRun(runIdRef.current);
+      }
       logAsStaticArrayRef.current.push({
         text: "*** Stopped by user ***",
         code: LogCode.StatusUpdate,
@@ -50,6 +50,135 @@ type AnalysisMetaData = {
     lastModifiedBy: string,
     scheduled: boolean,
     lastStatus: StageResult,
+    limits: RunLimits,
+    allowNetwork: boolean,
+    parameters: AnalysisParameter[],
+    secrets: string[],
+    keepOutputVersions: number | null,
+    lastSuccessFingerprint: string | null,
+    entryPoint: string | null,
+};
+
+export type RunLimits = {
+    timeoutSeconds?: number,
+    memoryMb?: number,
+    cpuSeconds?: number,
+};
+
+export type AnalysisParameter = {
+    name: string,
+    default: string,
+} & (
+    | { type: "string" }
+    | { type: "number" }
+    | { type: "date" }
+    | { type: "choice", options: string[] }
+);
+
+export type ParameterValue = {
+    name: string,
+    value: string,
+};
+
+export type AnalysisFile = {
+    fileName: string,
+    code: string,
+};
+
+export type Library = {
+    version: number | null,
+    files: AnalysisFile[],
+};
+
+export type LibraryVersion = {
+    version: number,
+    createdAt: string,
+    createdBy: string,
+    change: string,
+};
+
+export type Schedule = {
+    id: string,
+    name: string,
+    rule: ScheduleRule,
+    target: ScheduleTarget,
+    paused: boolean,
+    nextDueAt: string | null,
+    lastStartedAt: string | null,
+};
+
+export type ScheduleRule =
+    | { type: "daily", time: string }
+    | { type: "weekly", weekday: string, time: string }
+    | { type: "cron", expression: string };
+
+export type ScheduleTarget =
+    | { type: "analysis", analysisId: string }
+    | { type: "topic", topic: string }
+    | { type: "tag", tag: string }
+    | { type: "scheduled" };
+
+export type UpcomingRun = {
+    scheduleId: string,
+    scheduleName: string,
+    dueAt: string,
+    analysisIds: string[],
+};
+
+export type SchedulerPass = {
+    id: string,
+    trigger: string,
+    startedAt: string,
+    endedAt: string | null,
+    analysisIds: string[],
+    results: SchedulerPassResult[],
+    skipped: SkippedAnalysis[],
+    stopped: boolean,
+    force: boolean,
+};
+
+export type SchedulerPassResult = {
+    analysisId: string,
+    runId: string,
+    status: StageResult,
+    startedAt: string,
+    endedAt: string | null,
+    durationSeconds: number | null,
+};
+
+export type SkippedAnalysis = {
+    analysisId: string,
+    reason: string,
+};
+
+export type RunWithDependenciesRequest = {
+    analysisId: string,
+    dependencies: "downstream" | "upstream",
+    force?: boolean,
+};
+
+export type DependencyProblem = { message: string } & (
+    | { type: "cycle", analysisIds: string[] }
+    | { type: "missingAnalysis", analysisId: string, upstreamAnalysisId: string, fileName: string }
+    | { type: "missingDataFile", analysisId: string, fileName: string }
+    | { type: "undeclaredOutput", analysisId: string, upstreamAnalysisId: string, fileName: string }
+);
+
+export type SavedAnalysis = {
+    analysisPackage: AnalysisPackage,
+    problems: DependencyProblem[],
+};
+
+export type SecretSummary = {
+    name: string,
+    updatedAt: string,
+    updatedBy: string,
+    analysisIds: string[],
+};
+
+export type GrantSecretRequest = {
+    name: string,
+    analysisIds: string[],
 };
 
 export type InputFile = {
@@ -58,11 +187,17 @@ export type InputFile = {
     fileName: string,
 };
 
+// fileName can be an exact path ("figs/map.png"), a glob ("figs/*.png") or a folder ("figs/")
 export type OutputFile = {
     fileName: string,
     public: boolean,
 };
 
+export type StoredOutputFile = {
+    fileName: string,
+    public: boolean,
+};
+
 export type DataFile = {
     fileName: string,
     date: string,
@@ -113,6 +248,7 @@ export enum FolderType {
 export enum LanguageType {
     R = "r",
     Stata = "stata",
+    Python = "python",
 }
 
 export type CheckFileResponse = {
@@ -120,11 +256,25 @@ export type CheckFileResponse = {
     date: string,
     size: number,
     public: boolean,
+    version: number | null,
+};
+
+export type OutputFileVersion = {
+    version: number,
+    runId: string,
+    createdAt: string,
+    size: number,
+    current: boolean,
 };
 
 ////////////////////////////////////
 
-export type RealTimeMessage = RTMWaiting | RTMLog | RTMHeartbeat | RTMStage | RTMEnd;
+export type RealTimeMessage = RTMRunStarted | RTMWaiting | RTMLog | RTMHeartbeat | RTMStage | RTMExit | RTMEnd | RTMScriptDiagnostic;
+
+export type RTMRunStarted = {
+    msgType: MessageType.RunStarted,
+    runId: string,
+};
 
 export type RTMStage = {
     msgType: MessageType.Stage,
@@ -146,19 +296,44 @@ export type RTMHeartbeat = {
     msgType: MessageType.Heartbeat,
 };
 
+export type RTMExit = {
+    msgType: MessageType.Exit,
+    exitCode: number | null,
+};
+
+export type RTMScriptDiagnostic = {
+    msgType: MessageType.ScriptError | MessageType.ScriptWarning,
+    log: string,
+    diagnostic: ScriptDiagnostic,
+};
+
+export type ScriptDiagnostic = {
+    message: string,
+    returnCode: number | null,
+    command: string | null,
+    line: number | null,
+    traceback: string[],
+};
+
 export type RTMEnd = {
-    msgType: MessageType.EndSuccess | MessageType.EndFailure,
+    msgType: MessageType.EndSuccess | MessageType.EndFailure | MessageType.EndCancelled,
+    stageResult: StageResult,
 };
 
 
 export enum MessageType {
+    RunStarted = "RunStarted",
     Heartbeat = "Heartbeat",
     Waiting = "Waiting",
     Stage = "Stage",
     LogOut = "LogOut",
     LogErr = "LogErr",
+    Exit = "Exit",
     EndSuccess = "EndSuccess",
     EndFailure = "EndFailure",
+    EndCancelled = "EndCancelled",
+    ScriptError = "ScriptError",
+    ScriptWarning = "ScriptWarning",
 }
 
 export enum Stage {
@@ -173,8 +348,34 @@ export enum StageResult {
     Pending = "Pending",
     Success = "Success",
     Failure = "Failure",
+    LimitExceeded = "LimitExceeded",
+    Cancelled = "Cancelled",
+    UpToDate = "UpToDate",
 }
 
+export type RunRecord = {
+    runId: string,
+    analysisId: string,
+    startedAt: string,
+    endedAt: string | null,
+    startedBy: string,
+    status: StageResult,
+    stages: StageRecord[],
+    exitCode: number | null,
+    errors: ScriptDiagnostic[],
+    warnings: ScriptDiagnostic[],
+    parameters: ParameterValue[],
+    libraryVersion: number | null,
+    log: RTMLog[],
+};
+
+export type StageRecord = {
+    stage: Stage,
+    result: StageResult,
+    startedAt: string,
+    endedAt: string | null,
+};
+
 export enum LogCode {
     StatusUpdate,
     Out,
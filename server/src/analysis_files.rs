@@ -0,0 +1,181 @@
+use super::*;
+
+// Besides its code, an analysis can have other source files (helper functions etc.), kept in
+// _FOLDER_NAME_FILES in the analysis folder. They are copied into the workspace with the same
+// relative paths, so the script can source() / do them. If metadata "entryPoint" names one of
+// them, that file is run instead of the code.
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnalysisFile {
+    #[serde(rename = "fileName")]
+    pub file_name: String,
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SaveAnalysisFileRequest {
+    #[serde(rename = "analysisId")]
+    pub analysis_id: String,
+    #[serde(rename = "fileName")]
+    pub file_name: String,
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RenameAnalysisFileRequest {
+    #[serde(rename = "analysisId")]
+    pub analysis_id: String,
+    #[serde(rename = "fileName")]
+    pub file_name: String,
+    #[serde(rename = "newFileName")]
+    pub new_file_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteAnalysisFileRequest {
+    #[serde(rename = "analysisId")]
+    pub analysis_id: String,
+    #[serde(rename = "fileName")]
+    pub file_name: String,
+}
+
+// Relative paths of plain names only, so they can't escape the folder, clash with the hidden
+// files the server keeps there, or break out of the quotes in r_wrapper.R
+pub fn is_valid_analysis_file_name(file_name: &str) -> bool {
+    !file_name.is_empty()
+        && file_name.split('/').all(|x| {
+            !x.is_empty()
+                && !x.starts_with('.')
+                && x.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+        })
+}
+
+pub fn get_analysis_file_names(analysis_id: &String) -> Vec<String> {
+    get_relative_file_paths(&get_path_to_analysis_files(analysis_id))
+}
+
+pub fn get_analysis_files(analysis_id: &String) -> Option<Vec<AnalysisFile>> {
+    let folder_path = get_path_to_analysis_files(analysis_id);
+    let mut analysis_files = Vec::new();
+    for file_name in get_analysis_file_names(analysis_id) {
+        let code = read_to_string(folder_path.join(&file_name)).ok()?;
+        analysis_files.push(AnalysisFile { file_name, code });
+    }
+    Some(analysis_files)
+}
+
+fn write_analysis_file(analysis_id: &String, file_name: &str, code: &str) -> Option<()> {
+    let file_path = get_path_to_analysis_files(analysis_id).join(file_name);
+    create_dir_all(file_path.parent()?).ok()?;
+    write(file_path, code).ok()
+}
+
+fn touch_analysis(analysis_id: &String, user: &UserWithRoles) -> Option<()> {
+    let mut m = get_metadata_from_analysis_id(analysis_id)?;
+    m.last_modified_at = chrono::Utc::now();
+    m.last_modified_by = user.email.clone();
+    save_metadata(analysis_id, &m)
+}
+
+///////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////
+
+#[get("/analysis/<analysis_id>/files")]
+fn all_analysis_files(
+    _user: UserWithRoles,
+    analysis_id: String,
+) -> Option<Json<Vec<AnalysisFile>>> {
+    let analysis_files = get_analysis_files(&analysis_id)?;
+    Some(Json(analysis_files))
+}
+
+#[post("/createanalysisfile", format = "application/json", data = "<sfr>")]
+fn create_analysis_file(
+    user: UserWithRoles,
+    sfr: Json<SaveAnalysisFileRequest>,
+) -> Option<Json<Vec<AnalysisFile>>> {
+    if !user.can_edit || !is_valid_analysis_file_name(&sfr.file_name) {
+        return None;
+    }
+    get_metadata_from_analysis_id(&sfr.analysis_id)?;
+    if get_analysis_file_names(&sfr.analysis_id).contains(&sfr.file_name) {
+        return None;
+    }
+    write_analysis_file(&sfr.analysis_id, &sfr.file_name, &sfr.code)?;
+    touch_analysis(&sfr.analysis_id, &user)?;
+    let analysis_files = get_analysis_files(&sfr.analysis_id)?;
+    Some(Json(analysis_files))
+}
+
+#[post("/updateanalysisfile", format = "application/json", data = "<sfr>")]
+fn update_analysis_file(
+    user: UserWithRoles,
+    sfr: Json<SaveAnalysisFileRequest>,
+) -> Option<Json<Vec<AnalysisFile>>> {
+    if !user.can_edit || !get_analysis_file_names(&sfr.analysis_id).contains(&sfr.file_name) {
+        return None;
+    }
+    write_analysis_file(&sfr.analysis_id, &sfr.file_name, &sfr.code)?;
+    touch_analysis(&sfr.analysis_id, &user)?;
+    let analysis_files = get_analysis_files(&sfr.analysis_id)?;
+    Some(Json(analysis_files))
+}
+
+#[post("/renameanalysisfile", format = "application/json", data = "<rfr>")]
+fn rename_analysis_file(
+    user: UserWithRoles,
+    rfr: Json<RenameAnalysisFileRequest>,
+) -> Option<Json<Vec<AnalysisFile>>> {
+    if !user.can_edit || !is_valid_analysis_file_name(&rfr.new_file_name) {
+        return None;
+    }
+    let file_names = get_analysis_file_names(&rfr.analysis_id);
+    if !file_names.contains(&rfr.file_name) || file_names.contains(&rfr.new_file_name) {
+        return None;
+    }
+    let folder_path = get_path_to_analysis_files(&rfr.analysis_id);
+    let new_file_path = folder_path.join(&rfr.new_file_name);
+    create_dir_all(new_file_path.parent()?).ok()?;
+    rename(folder_path.join(&rfr.file_name), new_file_path).ok()?;
+    // The entry point follows the file
+    let mut m = get_metadata_from_analysis_id(&rfr.analysis_id)?;
+    if m.entry_point.as_ref() == Some(&rfr.file_name) {
+        m.entry_point = Some(rfr.new_file_name.clone());
+    }
+    m.last_modified_at = chrono::Utc::now();
+    m.last_modified_by = user.email.clone();
+    save_metadata(&rfr.analysis_id, &m)?;
+    let analysis_files = get_analysis_files(&rfr.analysis_id)?;
+    Some(Json(analysis_files))
+}
+
+#[post("/deleteanalysisfile", format = "application/json", data = "<dfr>")]
+fn delete_analysis_file(
+    user: UserWithRoles,
+    dfr: Json<DeleteAnalysisFileRequest>,
+) -> Option<Json<Vec<AnalysisFile>>> {
+    if !user.can_edit || !get_analysis_file_names(&dfr.analysis_id).contains(&dfr.file_name) {
+        return None;
+    }
+    // Choose another entry point first
+    let m = get_metadata_from_analysis_id(&dfr.analysis_id)?;
+    if m.entry_point.as_ref() == Some(&dfr.file_name) {
+        return None;
+    }
+    remove_file(get_path_to_analysis_files(&dfr.analysis_id).join(&dfr.file_name)).ok()?;
+    touch_analysis(&dfr.analysis_id, &user)?;
+    let analysis_files = get_analysis_files(&dfr.analysis_id)?;
+    Some(Json(analysis_files))
+}
+
+pub fn analysis_file_routes() -> Vec<rocket::Route> {
+    routes![
+        all_analysis_files,
+        create_analysis_file,
+        update_analysis_file,
+        rename_analysis_file,
+        delete_analysis_file
+    ]
+}
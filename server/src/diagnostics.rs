@@ -0,0 +1,262 @@
+use super::*;
+
+// Works out what went wrong from what the interpreter printed, so that a failed run can say
+// "r(601) at line 3" instead of leaving people to read through the whole log.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum Diagnostics {
+    // Stata echoes every line of the do-file (". command") and ends a failed one with "r(###);"
+    #[serde(rename = "stataLog")]
+    StataLog,
+    // Runs R scripts through r_wrapper.R, which reports warnings, the error and a traceback
+    #[serde(rename = "rWrapper")]
+    RWrapper,
+}
+
+const R_WRAPPER: &str = include_str!("r_wrapper.R");
+const R_WRAPPER_MARKER: &str = "@@comsa@@";
+
+impl ScriptDiagnostic {
+    pub fn summary(&self) -> String {
+        let mut summary = String::new();
+        if let Some(line) = self.line {
+            summary.push_str(&format!("Line {}", line));
+        }
+        if let Some(command) = &self.command {
+            if !summary.is_empty() {
+                summary.push_str(", ");
+            }
+            summary.push_str(&format!("\"{}\"", command));
+        }
+        if !summary.is_empty() {
+            summary.push_str(": ");
+        }
+        summary.push_str(&self.message);
+        if let Some(return_code) = self.return_code {
+            summary.push_str(&format!(" (r({}))", return_code));
+        }
+        summary
+    }
+}
+
+// Picks the r_wrapper.R lines out of stderr, everything else is left as normal log
+// For the script to run, a valid analysis file name or _FILE_NAME_MYSCRIPT, so no quoting needed
+pub fn get_r_wrapper(script_file_name: &str) -> String {
+    R_WRAPPER.replace("{script}", script_file_name)
+}
+
+#[derive(Default)]
+pub struct RWrapperReader {
+    warning_count: usize,
+    traceback: Vec<String>,
+    error_line: Option<Option<u32>>,
+}
+
+impl RWrapperReader {
+    pub fn read_line(&mut self, line: &str) -> Option<(MessageType, ScriptDiagnostic)> {
+        let mut fields = line.strip_prefix(R_WRAPPER_MARKER)?.splitn(3, '\t');
+        let kind = fields.next()?;
+        let line_number = fields.next()?.parse::<u32>().ok();
+        let text = fields.next()?.to_string();
+        let diagnostic = ScriptDiagnostic {
+            message: text,
+            return_code: None,
+            command: None,
+            line: line_number,
+            traceback: Vec::new(),
+        };
+        match kind {
+            "warning" => {
+                self.warning_count += 1;
+                Some((MessageType::ScriptWarning, diagnostic))
+            }
+            // The traceback comes before the error it belongs to
+            "call" => {
+                self.traceback.push(diagnostic.message);
+                None
+            }
+            "error" => {
+                self.error_line = Some(line_number);
+                Some((
+                    MessageType::ScriptError,
+                    ScriptDiagnostic {
+                        traceback: std::mem::take(&mut self.traceback),
+                        ..diagnostic
+                    },
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_wrapper_line(line: &str) -> bool {
+        line.starts_with(R_WRAPPER_MARKER)
+    }
+
+    // e.g. "3 warnings, failed at line 42"
+    pub fn summary(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        if self.warning_count > 0 {
+            parts.push(format!(
+                "{} warning{}",
+                self.warning_count,
+                if self.warning_count == 1 { "" } else { "s" }
+            ));
+        }
+        match self.error_line {
+            Some(Some(line)) => parts.push(format!("failed at line {}", line)),
+            Some(None) => parts.push("failed".to_string()),
+            None => {}
+        }
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(", "))
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.error_line.is_some()
+    }
+}
+
+// What a line of a Stata log is
+enum StataLogLine<'a> {
+    // ". command", a line of the do-file
+    Command(&'a str),
+    // "  3. command", the 3rd line of a loop (or program) body, echoed while it's being defined
+    BlockCommand(u32, &'a str),
+    // "> more", the rest of the line above (a "///" continuation or just a long line)
+    Continuation(&'a str),
+    ReturnCode(i32),
+    Other(&'a str),
+}
+
+fn get_stata_log_line(line: &str) -> StataLogLine<'_> {
+    if let Some(command) = line.strip_prefix(". ") {
+        return StataLogLine::Command(command.trim());
+    }
+    if line == "." {
+        return StataLogLine::Command("");
+    }
+    if let Some(rest) = line.strip_prefix("> ") {
+        return StataLogLine::Continuation(rest.trim());
+    }
+    let trimmed = line.trim();
+    if let Some(return_code) = trimmed
+        .strip_prefix("r(")
+        .and_then(|x| x.strip_suffix(");"))
+        .and_then(|x| x.parse::<i32>().ok())
+    {
+        return StataLogLine::ReturnCode(return_code);
+    }
+    if line.starts_with(' ') {
+        if let Some((number, command)) = trimmed.split_once(". ") {
+            if let Ok(number) = number.parse::<u32>() {
+                return StataLogLine::BlockCommand(number, command.trim());
+            }
+        }
+    }
+    StataLogLine::Other(trimmed)
+}
+
+// The first error in the log, with the command that raised it and its line in the do-file.
+// The line is counted from the echoed commands (Stata echoes blank lines and comments too),
+// then checked against the script, because long commands are wrapped in the log.
+pub fn get_stata_error(log: &str, script: &str) -> Option<ScriptDiagnostic> {
+    let lines: Vec<StataLogLine> = log.lines().map(get_stata_log_line).collect();
+    let error_index = lines
+        .iter()
+        .position(|x| matches!(x, StataLogLine::ReturnCode(_)))?;
+    let return_code = match lines[error_index] {
+        StataLogLine::ReturnCode(v) => v,
+        _ => return None,
+    };
+
+    // Count the do-file's lines up to the error, skipping anything echoed by nested do-files
+    let mut started = false;
+    let mut depth = 0;
+    let mut line_number: u32 = 0;
+    let mut block_start: u32 = 0;
+    let mut command: Option<(u32, String)> = None;
+    for log_line in lines[..error_index].iter() {
+        match log_line {
+            StataLogLine::Command(text) if !started => {
+                started = text.starts_with("do ");
+            }
+            StataLogLine::Command(text) if depth == 0 => {
+                line_number += 1;
+                block_start = line_number;
+                if text.starts_with("do ") {
+                    depth += 1;
+                }
+                command = Some((line_number, text.to_string()));
+            }
+            StataLogLine::BlockCommand(number, text) if started && depth == 0 => {
+                line_number = block_start + number - 1;
+                command = Some((line_number, text.to_string()));
+            }
+            StataLogLine::Continuation(text) if started && depth == 0 => {
+                if let Some((_, command_text)) = command.as_mut() {
+                    command_text.push(' ');
+                    command_text.push_str(text.trim_start_matches("///").trim());
+                }
+            }
+            StataLogLine::Other("end of do-file") if depth > 0 => {
+                depth -= 1;
+            }
+            _ => {}
+        }
+    }
+
+    // The message is what was printed right before "r(###);"
+    let mut message_lines: Vec<&str> = Vec::new();
+    for log_line in lines[..error_index].iter().rev() {
+        match log_line {
+            StataLogLine::Other(text) if !text.is_empty() => message_lines.insert(0, text),
+            _ => break,
+        }
+    }
+    let message = if message_lines.is_empty() {
+        format!("Stata error r({})", return_code)
+    } else {
+        message_lines.join(" ")
+    };
+
+    let (line, command) = match command {
+        Some((estimate, text)) => {
+            let line = find_script_line(script, &text, estimate).unwrap_or(estimate);
+            let text = text
+                .replace("///", " ")
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ");
+            (Some(line), Some(text).filter(|x| !x.is_empty()))
+        }
+        None => (None, None),
+    };
+    Some(ScriptDiagnostic {
+        message,
+        return_code: Some(return_code),
+        command,
+        line,
+        traceback: Vec::new(),
+    })
+}
+
+// The line of the script that starts the command, the closest one to the estimate if the
+// same command is used more than once
+fn find_script_line(script: &str, command: &str, estimate: u32) -> Option<u32> {
+    let first_word = command.split_whitespace().next()?;
+    script
+        .lines()
+        .enumerate()
+        .map(|(i, x)| (i as u32 + 1, x.trim()))
+        .filter(|(_, x)| {
+            x.split_whitespace().next() == Some(first_word)
+                && command.starts_with(x.trim_end_matches("///").trim())
+        })
+        .map(|(i, _)| i)
+        .min_by_key(|i| (*i as i64 - estimate as i64).abs())
+}
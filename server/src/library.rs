@@ -0,0 +1,337 @@
+use super::*;
+
+// Scripts shared by every analysis (R functions, Stata ado files, ...), kept in _LIBRARY_FOLDER.
+// Every change makes a new version (a full copy, they are small), and .current says which one
+// runs use. Old versions are kept, so the version recorded with a run can always be looked at.
+//
+// Each run gets a copy of the current version in _FOLDER_NAME_LIBRARY in its workspace, which
+// the runner puts on the source path / adopath / PYTHONPATH (see Runner::command).
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LibraryVersion {
+    pub version: u64,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "createdBy")]
+    pub created_by: String,
+    // e.g. "Updated agebands.R"
+    pub change: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Library {
+    // None until the first file is added
+    pub version: Option<u64>,
+    pub files: Vec<AnalysisFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SaveLibraryFileRequest {
+    #[serde(rename = "fileName")]
+    pub file_name: String,
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RenameLibraryFileRequest {
+    #[serde(rename = "fileName")]
+    pub file_name: String,
+    #[serde(rename = "newFileName")]
+    pub new_file_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteLibraryFileRequest {
+    #[serde(rename = "fileName")]
+    pub file_name: String,
+}
+
+pub fn get_current_library_version() -> Option<u64> {
+    read_to_string(PathBuf::from(_LIBRARY_FOLDER).join(_FILE_NAME_CURRENT_VERSION))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+pub fn get_path_to_library_version(version: u64) -> PathBuf {
+    PathBuf::from(_LIBRARY_FOLDER).join(version.to_string())
+}
+
+// Newest first
+pub fn get_library_versions() -> Vec<LibraryVersion> {
+    let entries = match read_dir(_LIBRARY_FOLDER) {
+        Ok(v) => v,
+        Err(_) => return Vec::new(),
+    };
+    let mut library_versions: Vec<LibraryVersion> = entries
+        .flatten()
+        .filter(|x| x.file_name().to_string_lossy().parse::<u64>().is_ok())
+        .filter_map(|x| read_to_string(x.path().join(_FILE_NAME_VERSION_INFO)).ok())
+        .filter_map(|x| serde_json::from_str(&x).ok())
+        .collect();
+    library_versions.sort_by_key(|x| std::cmp::Reverse(x.version));
+    library_versions
+}
+
+fn get_library(version: Option<u64>) -> Option<Library> {
+    let version = match version {
+        Some(v) => v,
+        None => {
+            return Some(Library {
+                version: None,
+                files: Vec::new(),
+            })
+        }
+    };
+    let folder_path = get_path_to_library_version(version);
+    let mut files = Vec::new();
+    for file_name in get_relative_file_paths(&folder_path) {
+        let code = read_to_string(folder_path.join(&file_name)).ok()?;
+        files.push(AnalysisFile { file_name, code });
+    }
+    Some(Library {
+        version: Some(version),
+        files,
+    })
+}
+
+// A copy of the current version with the change made by "edit" becomes the next version
+fn new_library_version<F>(user: &UserWithRoles, change: String, edit: F) -> Option<u64>
+where
+    F: FnOnce(&Path) -> Option<()>,
+{
+    let library_path = PathBuf::from(_LIBRARY_FOLDER);
+    let staging_path = library_path.join(format!(".staging-{}", Uuid::new_v4()));
+    let version = stage_library_version(&staging_path, user, change, edit)
+        .and_then(|_| rename_staged_library_version(&staging_path));
+    let version = match version {
+        Some(v) => v,
+        None => {
+            let _ = remove_dir_all(&staging_path);
+            return None;
+        }
+    };
+    let temp_current_path = library_path.join(format!(".current-{}", version));
+    write(&temp_current_path, version.to_string()).ok()?;
+    rename(
+        &temp_current_path,
+        library_path.join(_FILE_NAME_CURRENT_VERSION),
+    )
+    .ok()?;
+    Some(version)
+}
+
+fn stage_library_version<F>(
+    staging_path: &Path,
+    user: &UserWithRoles,
+    change: String,
+    edit: F,
+) -> Option<()>
+where
+    F: FnOnce(&Path) -> Option<()>,
+{
+    create_dir_all(staging_path).ok()?;
+    if let Some(version) = get_current_library_version() {
+        let current_path = get_path_to_library_version(version);
+        for file_name in get_relative_file_paths(&current_path) {
+            let to_path = staging_path.join(&file_name);
+            create_dir_all(to_path.parent()?).ok()?;
+            copy(current_path.join(&file_name), to_path).ok()?;
+        }
+    }
+    edit(staging_path)?;
+    let library_version = LibraryVersion {
+        version: 0,
+        created_at: chrono::Utc::now(),
+        created_by: user.email.clone(),
+        change,
+    };
+    let json_string = serde_json::to_string_pretty(&library_version).ok()?;
+    write(staging_path.join(_FILE_NAME_VERSION_INFO), json_string).ok()?;
+    Some(())
+}
+
+fn rename_staged_library_version(staging_path: &Path) -> Option<u64> {
+    let info_path = staging_path.join(_FILE_NAME_VERSION_INFO);
+    let mut library_version: LibraryVersion =
+        serde_json::from_str(&read_to_string(&info_path).ok()?).ok()?;
+    for _ in 0..10 {
+        let version = get_library_versions().first().map_or(1, |x| x.version + 1);
+        library_version.version = version;
+        let json_string = serde_json::to_string_pretty(&library_version).ok()?;
+        write(&info_path, json_string).ok()?;
+        if rename(staging_path, get_path_to_library_version(version)).is_ok() {
+            return Some(version);
+        }
+    }
+    None
+}
+
+// Sources every R file in the library when R starts (set as R_PROFILE_USER)
+const R_LIBRARY_PROFILE: &str = r#"local({
+  library_path <- Sys.getenv("COMSA_LIBRARY_PATH")
+  for (f in sort(list.files(library_path, pattern = "\\.[Rr]$", recursive = TRUE, full.names = TRUE))) {
+    sys.source(f, envir = globalenv(), keep.source = TRUE)
+  }
+})
+"#;
+
+// Env vars that make the library in the workspace available to the script: COMSA_LIBRARY_PATH
+// for all languages (not LIBRARY_PATH, which compilers read), plus the R profile, the Stata
+// adopath or PYTHONPATH
+pub fn get_library_env(
+    language: &LanguageType,
+    temp_path: &Path,
+    library_path: &Path,
+) -> Option<Vec<(String, String)>> {
+    let library_path_str = library_path.to_str()?.to_string();
+    let mut env = vec![("COMSA_LIBRARY_PATH".to_string(), library_path_str.clone())];
+    match language {
+        LanguageType::R => {
+            let profile_path = temp_path.canonicalize().ok()?.join(_FILE_NAME_R_PROFILE);
+            write(&profile_path, R_LIBRARY_PROFILE).ok()?;
+            env.push((
+                "R_PROFILE_USER".to_string(),
+                profile_path.to_str()?.to_string(),
+            ));
+        }
+        // The default adopath, with the library after Stata's own ado files
+        LanguageType::Stata => env.push((
+            "S_ADO".to_string(),
+            format!(
+                "BASE;SITE;\"{}\";.;PERSONAL;PLUS;OLDPLACE",
+                library_path_str
+            ),
+        )),
+        LanguageType::Python => env.push(("PYTHONPATH".to_string(), library_path_str)),
+        LanguageType::Other(_) => {}
+    }
+    Some(env)
+}
+
+// For the run's workspace
+pub fn copy_library_version(version: u64, to_path: &Path) -> Option<()> {
+    let from_path = get_path_to_library_version(version);
+    for file_name in get_relative_file_paths(&from_path) {
+        let file_path = to_path.join(&file_name);
+        create_dir_all(file_path.parent()?).ok()?;
+        copy(from_path.join(&file_name), file_path).ok()?;
+    }
+    Some(())
+}
+
+fn get_library_file_names() -> Vec<String> {
+    match get_current_library_version() {
+        Some(version) => get_relative_file_paths(&get_path_to_library_version(version)),
+        None => Vec::new(),
+    }
+}
+
+///////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////
+
+#[get("/library")]
+fn current_library(_user: UserWithRoles) -> Option<Json<Library>> {
+    let library = get_library(get_current_library_version())?;
+    Some(Json(library))
+}
+
+#[get("/library/versions")]
+fn all_library_versions(_user: UserWithRoles) -> Json<Vec<LibraryVersion>> {
+    Json(get_library_versions())
+}
+
+#[get("/library/versions/<version>")]
+fn library_version(_user: UserWithRoles, version: u64) -> Option<Json<Library>> {
+    if !get_path_to_library_version(version).exists() {
+        return None;
+    }
+    let library = get_library(Some(version))?;
+    Some(Json(library))
+}
+
+#[post("/createlibraryfile", format = "application/json", data = "<sfr>")]
+fn create_library_file(
+    user: UserWithRoles,
+    sfr: Json<SaveLibraryFileRequest>,
+) -> Option<Json<Library>> {
+    if !user.can_edit
+        || !is_valid_analysis_file_name(&sfr.file_name)
+        || get_library_file_names().contains(&sfr.file_name)
+    {
+        return None;
+    }
+    let version = new_library_version(&user, format!("Added {}", sfr.file_name), |path| {
+        let file_path = path.join(&sfr.file_name);
+        create_dir_all(file_path.parent()?).ok()?;
+        write(file_path, &sfr.code).ok()
+    })?;
+    let library = get_library(Some(version))?;
+    Some(Json(library))
+}
+
+#[post("/updatelibraryfile", format = "application/json", data = "<sfr>")]
+fn update_library_file(
+    user: UserWithRoles,
+    sfr: Json<SaveLibraryFileRequest>,
+) -> Option<Json<Library>> {
+    if !user.can_edit || !get_library_file_names().contains(&sfr.file_name) {
+        return None;
+    }
+    let version = new_library_version(&user, format!("Updated {}", sfr.file_name), |path| {
+        write(path.join(&sfr.file_name), &sfr.code).ok()
+    })?;
+    let library = get_library(Some(version))?;
+    Some(Json(library))
+}
+
+#[post("/renamelibraryfile", format = "application/json", data = "<rfr>")]
+fn rename_library_file(
+    user: UserWithRoles,
+    rfr: Json<RenameLibraryFileRequest>,
+) -> Option<Json<Library>> {
+    if !user.can_edit || !is_valid_analysis_file_name(&rfr.new_file_name) {
+        return None;
+    }
+    let file_names = get_library_file_names();
+    if !file_names.contains(&rfr.file_name) || file_names.contains(&rfr.new_file_name) {
+        return None;
+    }
+    let change = format!("Renamed {} to {}", rfr.file_name, rfr.new_file_name);
+    let version = new_library_version(&user, change, |path| {
+        let new_file_path = path.join(&rfr.new_file_name);
+        create_dir_all(new_file_path.parent()?).ok()?;
+        rename(path.join(&rfr.file_name), new_file_path).ok()
+    })?;
+    let library = get_library(Some(version))?;
+    Some(Json(library))
+}
+
+#[post("/deletelibraryfile", format = "application/json", data = "<dfr>")]
+fn delete_library_file(
+    user: UserWithRoles,
+    dfr: Json<DeleteLibraryFileRequest>,
+) -> Option<Json<Library>> {
+    if !user.can_edit || !get_library_file_names().contains(&dfr.file_name) {
+        return None;
+    }
+    let version = new_library_version(&user, format!("Deleted {}", dfr.file_name), |path| {
+        remove_file(path.join(&dfr.file_name)).ok()
+    })?;
+    let library = get_library(Some(version))?;
+    Some(Json(library))
+}
+
+pub fn library_routes() -> Vec<rocket::Route> {
+    routes![
+        current_library,
+        all_library_versions,
+        library_version,
+        create_library_file,
+        update_library_file,
+        rename_library_file,
+        delete_library_file
+    ]
+}
@@ -8,54 +8,90 @@ use linemux::MuxedLines;
 use rocket::data::{Limits, ToByteUnit};
 use rocket::form::Form;
 use rocket::fs::{FileName, FileServer, NamedFile, TempFile};
-use rocket::http::{Cookie, CookieJar, SameSite};
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
 use rocket::request::FromParam;
 use rocket::request::{self, FromRequest, Request};
+use rocket::response::status::Custom;
 use rocket::response::stream::{Event, EventStream};
 use rocket::serde::json::Json;
 use rocket::tokio::select;
 use rocket::tokio::sync::mpsc::Sender;
-use rocket::tokio::time::{sleep, Duration};
+use rocket::tokio::time::{sleep, timeout, Duration};
 use rocket::Shutdown;
 use rocket::State;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use tokio::io::AsyncBufReadExt;
 
 use std::fs::{
-    copy, metadata, read_dir, read_to_string, remove_dir_all, remove_file, write, DirBuilder,
+    copy, create_dir_all, metadata, read, read_dir, read_to_string, remove_dir_all, remove_file,
+    rename, write, DirBuilder, File,
 };
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
 mod cors;
 mod types;
 use types::*;
-mod run_r;
-mod run_stata;
+mod runners;
 mod utils;
-use run_r::*;
-use run_stata::*;
+use runners::*;
 use utils::*;
 mod real_time;
 use real_time::*;
+mod runs;
+use runs::*;
+mod secrets;
+use secrets::*;
+mod diagnostics;
+use diagnostics::*;
+mod analysis_files;
+use analysis_files::*;
+mod library;
+use library::*;
+mod schedules;
+use schedules::*;
+mod scheduler;
+use scheduler::*;
+mod validation;
+use validation::*;
 mod users_and_sessions;
 use users_and_sessions::*;
 
 const _FILE_NAME_MYSCRIPT: &str = ".script";
-const _FILE_NAME_STATALOG: &str = ".log";
+const _FILE_NAME_R_WRAPPER: &str = ".wrapper.R";
 const _FILE_NAME_MYMETADATA: &str = ".metadata.json";
+const _FOLDER_NAME_RUNS: &str = ".runs";
+const _FOLDER_NAME_VERSIONS: &str = ".versions";
+const _FOLDER_NAME_FILES: &str = ".files";
+const _FOLDER_NAME_LIBRARY: &str = ".library";
+const _FILE_NAME_R_PROFILE: &str = ".library.Rprofile";
+const _FILE_NAME_VERSION_INFO: &str = ".version.json";
+const _FILE_NAME_CURRENT_VERSION: &str = ".current";
 
 const _USERS_FILE_PATH: &str = "./admin/users.json";
 const _TOPICS_FILE_PATH: &str = "./admin/topics.json";
+const _RUNNERS_FILE_PATH: &str = "./admin/runners.json";
+const _SECRETS_FILE_PATH: &str = "./admin/secrets.json";
+const _SCHEDULES_FILE_PATH: &str = "./admin/schedules.json";
+const _SCHEDULE_CHECK_SECONDS: u64 = 30;
+const _SCHEDULER_PASSES_FOLDER: &str = "./admin/passes";
 
 const _ADMIN_FOLDER: &str = "./admin";
 const _ANALYSES_FOLDER: &str = "./analyses";
 const _DATA_FOLDER: &str = "./data";
+const _LIBRARY_FOLDER: &str = "./library";
 const _HTML_FOLDER: &str = "./html";
 const _TEMP_FOLDER: &str = "./temp";
 
+const _SCHEDULER_USER: &str = "Scheduler";
+const _FINISHED_RUN_LOG_MINUTES: u64 = 60;
+const _DEFAULT_MAX_PARALLEL_RUNS: usize = 3;
+const _DEFAULT_KEEP_OUTPUT_VERSIONS: usize = 10;
+
 struct DownloadFile(NamedFile);
 
 impl<'r> rocket::response::Responder<'r, 'static> for DownloadFile {
@@ -137,13 +173,15 @@ async fn update_topics(user: UserWithRoles, ut: Json<Vec<Topic>>) -> Option<()>
 /////////////////////////////////////////////////
 /////////////////////////////////////////////////
 
-#[get("/cf/<folder_type>/<analysis_id>/<file_name>")]
+// File names can be nested paths (e.g. /cf/analysis/<analysis_id>/figs/map.png)
+#[get("/cf/<folder_type>/<analysis_id>/<file_name..>")]
 fn check_file(
     _user: UserWithRoles,
     folder_type: FolderType,
     analysis_id: String,
-    file_name: String,
+    file_name: PathBuf,
 ) -> Json<CheckFileResponse> {
+    let file_name = file_name.to_string_lossy().to_string();
     match get_check_file_response(folder_type, &analysis_id, &file_name) {
         Some(v) => Json(v),
         None => Json(CheckFileResponse {
@@ -151,23 +189,26 @@ fn check_file(
             date: "".to_string(),
             size: 0,
             public: false,
+            version: None,
         }),
     }
 }
 
-#[get("/pvf/<folder_type>/<analysis_id>/<file_name>")]
+#[get("/pvf/<folder_type>/<analysis_id>/<file_name..>")]
 async fn stream_private_file(
     _user: UserWithRoles,
     folder_type: FolderType,
     analysis_id: String,
-    file_name: String,
+    file_name: PathBuf,
 ) -> Option<DownloadFile> {
+    let file_name = file_name.to_string_lossy().to_string();
     let path = get_path_to_file(&folder_type, &analysis_id, &file_name);
     NamedFile::open(path).await.ok().map(|nf| DownloadFile(nf))
 }
 
-#[get("/exf/<analysis_id>/<file_name>")]
-async fn stream_public_file(analysis_id: String, file_name: String) -> Option<DownloadFile> {
+#[get("/exf/<analysis_id>/<file_name..>")]
+async fn stream_public_file(analysis_id: String, file_name: PathBuf) -> Option<DownloadFile> {
+    let file_name = file_name.to_string_lossy().to_string();
     match get_file_public_status(&analysis_id, &file_name) {
         None => None,
         Some(false) => None,
@@ -192,6 +233,44 @@ fn get_analysis(_user: UserWithRoles, analysis_id: String) -> Option<Json<Analys
     Some(Json(a))
 }
 
+#[get("/analysis/<analysis_id>/outputs")]
+fn get_analysis_outputs(
+    _user: UserWithRoles,
+    analysis_id: String,
+) -> Option<Json<Vec<StoredOutputFile>>> {
+    let stored_output_files = get_stored_output_files(&analysis_id)?;
+    Some(Json(stored_output_files))
+}
+
+// Newest first
+#[get("/analysis/<analysis_id>/versions/<file_name..>")]
+fn get_analysis_output_versions(
+    _user: UserWithRoles,
+    analysis_id: String,
+    file_name: PathBuf,
+) -> Json<Vec<OutputFileVersion>> {
+    let file_name = file_name.to_string_lossy().to_string();
+    Json(get_output_file_versions(&analysis_id, &file_name))
+}
+
+#[get("/ovf/<analysis_id>/<version>/<file_name..>")]
+async fn stream_output_version_file(
+    _user: UserWithRoles,
+    analysis_id: String,
+    version: u64,
+    file_name: PathBuf,
+) -> Option<DownloadFile> {
+    let file_name = file_name.to_string_lossy().to_string();
+    let path = get_path_to_output_version_file(&analysis_id, version, &file_name);
+    NamedFile::open(path).await.ok().map(DownloadFile)
+}
+
+#[get("/analysis/<analysis_id>/runs")]
+fn get_analysis_runs(_user: UserWithRoles, analysis_id: String) -> Option<Json<Vec<RunRecord>>> {
+    let records = get_run_records(&analysis_id)?;
+    Some(Json(records))
+}
+
 #[post("/createanalysis", format = "application/json", data = "<na>")]
 fn create_analysis(
     user: UserWithRoles,
@@ -254,26 +333,49 @@ fn delete_analysis(user: UserWithRoles, analysis_id: String) -> Option<Json<Vec<
 fn update_analysis(
     user: UserWithRoles,
     ap: Json<AnalysisPackage>,
-) -> Option<Json<AnalysisPackage>> {
+) -> Option<Result<Json<SavedAnalysis>, RefusedSave>> {
     if !user.can_edit {
         return None;
     }
     let folder_path = PathBuf::from(_ANALYSES_FOLDER).join(&ap.id);
 
-    // Update code
-    let code_file_path = folder_path.join(_FILE_NAME_MYSCRIPT);
-    write(code_file_path, &ap.code).ok()?;
-
-    // Update metadata
+    // Check metadata
     let metadata_file_path = folder_path.join(_FILE_NAME_MYMETADATA);
     let mut m = get_metadata_from_path(&metadata_file_path)?;
     m.name = ap.metadata.name.clone();
     m.language = ap.metadata.language.clone();
     m.inputs = ap.metadata.inputs.clone();
     m.outputs = ap.metadata.outputs.clone();
+    if !m.outputs.iter().all(|x| x.is_valid()) {
+        return None;
+    }
     m.topic = ap.metadata.topic.clone();
     m.tags = ap.metadata.tags.clone();
     m.scheduled = ap.metadata.scheduled;
+    m.limits = ap.metadata.limits.clone();
+    m.allow_network = ap.metadata.allow_network;
+    m.keep_output_versions = ap.metadata.keep_output_versions;
+    m.parameters = ap.metadata.parameters.clone();
+    m.secrets = ap.metadata.secrets.clone();
+    m.entry_point = ap.metadata.entry_point.clone();
+    if let Some(entry_point) = &m.entry_point {
+        if !get_analysis_file_names(&ap.id).contains(entry_point) {
+            return None;
+        }
+    }
+    // Names and defaults must be valid
+    m.resolve_parameters(&HashMap::new()).ok()?;
+    // Mustn't add a dependency cycle or an input from a missing analysis
+    let problems = get_new_blocking_problems(&ap.id, &m)?;
+    if !problems.is_empty() {
+        return Some(Err(Custom(Status::UnprocessableEntity, Json(problems))));
+    }
+
+    // Update code
+    let code_file_path = folder_path.join(_FILE_NAME_MYSCRIPT);
+    write(code_file_path, &ap.code).ok()?;
+
+    // Update metadata
     m.last_modified_at = chrono::Utc::now();
     m.last_modified_by = user.email.clone();
     let json_string = serde_json::to_string_pretty(&m).ok()?;
@@ -281,83 +383,75 @@ fn update_analysis(
 
     // Get new analysis package
     let a = get_analysis_package(&ap.id)?;
-    Some(Json(a))
+    let problems = get_problems_involving(&ap.id, &a.metadata)?;
+    Some(Ok(Json(SavedAnalysis {
+        analysis_package: a,
+        problems,
+    })))
+}
+
+#[derive(FromForm)]
+struct RunOptions {
+    // Overrides parameter defaults, e.g. ?params.year=2020&params.region=north
+    params: HashMap<String, String>,
+    // Whether to run even if nothing changed since the last successful run. Defaults to true, as
+    // someone pressing Run wants it to run (e.g. to refresh data pulled with a secret)
+    force: Option<bool>,
 }
 
-#[get("/run/<analysis_id>")]
+#[get("/run/<analysis_id>?<options..>")]
 async fn run(
     user: UserWithRoles,
-    mut end: Shutdown,
+    end: Shutdown,
     analysis_id: String,
+    options: RunOptions,
     ttq: &State<TimTicketQueue>,
-) -> EventStream![] {
-    let mut tq = ttq.ticket_queue.clone();
+    tr: &State<TimRuns>,
+    tsec: &State<TimSecrets>,
+) -> Option<EventStream![]> {
+    let (run_id, _) = start_run(
+        ttq.inner().clone(),
+        tr.run_logs.clone(),
+        tsec.inner().clone(),
+        &analysis_id,
+        &user.email,
+        &options.params,
+        options.force.unwrap_or(true),
+    );
+    get_run_events(tr.run_logs.clone(), &run_id, end)
+}
 
-    // Make this buffer BIG (e.g. 65536) because scripts can output a lot of log messages at once, causing it to fill up
-    let (sender, mut receiver) = tokio::sync::mpsc::channel::<RealTimeMessage>(65536);
-    let jh = rocket::tokio::spawn(async move {
-        let (temp_path, id) = tq.add();
-        let mut position_in_queue = tq.get_position(&id);
-        while position_in_queue > 3 {
-            match sender.try_send(RealTimeMessage {
-                msg_type: MessageType::Waiting,
-                stage: None,
-                stage_result: None,
-                log: Some(format!(
-                    "Waiting for {} other analyses to finish",
-                    position_in_queue
-                )),
-            }) {
-                Ok(()) => {}
-                Err(_) => {
-                    tq.remove(&id);
-                    return;
-                }
-            }
-            sleep(Duration::from_millis(2000)).await;
-            position_in_queue = tq.get_position(&id);
-        }
-        let (end_status, msg_type) =
-            match analyze_one_inner(&analysis_id, &sender, &temp_path).await {
-                Some(_) => (StageResult::Success, MessageType::EndSuccess),
-                None => (StageResult::Failure, MessageType::EndFailure),
-            };
-        let _ =
-            update_metadata_after_run(&analysis_id, chrono::Utc::now(), &user.email, &end_status);
-        let _ = sender.try_send(RealTimeMessage {
-            msg_type,
-            stage: None,
-            stage_result: None,
-            log: None,
-        });
-        tq.remove(&id);
-    });
+#[get("/runs/<run_id>")]
+fn get_run(_user: UserWithRoles, run_id: String) -> Option<Json<RunRecord>> {
+    let id = Uuid::parse_str(&run_id).ok()?;
+    let record = get_run_record(&id)?;
+    Some(Json(record))
+}
 
-    EventStream! {
-        loop {
-            let msg = select! {
-                msg = receiver.recv() => {
-                    match msg {
-                    Some(msg) => msg,
-                    None => {
-                        println!("Analysis process finished completely and sender closed.");
-                        break;
-                    },
-                }
-                },
-                _ = &mut end => {
-                    println!("Server was shutdown");
-                    jh.abort();
-                    break;
-                },
-            };
-            yield Event::json(&msg);
-        }
+#[get("/runs/<run_id>/events")]
+async fn run_events(
+    _user: UserWithRoles,
+    end: Shutdown,
+    run_id: String,
+    tr: &State<TimRuns>,
+) -> Option<EventStream![]> {
+    let id = Uuid::parse_str(&run_id).ok()?;
+    get_run_events(tr.run_logs.clone(), &id, end)
+}
+
+#[post("/runs/<run_id>/cancel")]
+fn cancel_run(user: UserWithRoles, run_id: String, ttq: &State<TimTicketQueue>) -> Option<()> {
+    let id = Uuid::parse_str(&run_id).ok()?;
+    let mut tq = ttq.ticket_queue.clone();
+    let ticket = tq.get(&id)?;
+    if !user.is_admin && user.email != ticket.started_by {
+        return None;
     }
+    tq.cancel(&id)
 }
 
 #[get("/queue")]
-async fn get_queue(ttq: &State<TimTicketQueue>) -> String {
+async fn get_queue(_user: UserWithRoles, ttq: &State<TimTicketQueue>) -> String {
     let t: Vec<Ticket> = ttq.ticket_queue.lock().unwrap().clone();
     serde_json::to_string_pretty(&t).unwrap()
 }
@@ -370,9 +464,16 @@ async fn get_queue(ttq: &State<TimTicketQueue>) -> String {
 /////////////////////////////////////////////////
 /////////////////////////////////////////////////
 
-#[get("/start_scheduler")]
-async fn start_scheduler(scheduler_sender: &State<Sender<SchedulerCommand>>) -> Option<()> {
-    let _ = scheduler_sender.send(SchedulerCommand::Start).await;
+// ?force=true runs every scheduled analysis, even those that are up to date
+#[get("/start_scheduler?<force>")]
+async fn start_scheduler(
+    scheduler_sender: &State<Sender<SchedulerCommand>>,
+    force: Option<bool>,
+) -> Option<()> {
+    let force = force.unwrap_or(false);
+    let _ = scheduler_sender
+        .send(SchedulerCommand::Start { force })
+        .await;
     Some(())
 }
 
@@ -436,106 +537,120 @@ fn delete_data_file(user: UserWithRoles, file_name: String) -> Option<Json<Vec<D
 
 #[rocket::main]
 async fn main() {
-    run_startup_checker();
+    // Asking the interpreters for their versions waits on processes, so it's kept off the runtime
+    if let Err(e) = rocket::tokio::task::spawn_blocking(run_startup_checker).await {
+        std::panic::resume_unwind(e.into_panic());
+    }
 
     let tsm = TimSessionsMap::new_instance();
 
-    let ttq = TimTicketQueue {
-        ticket_queue: Arc::new(Mutex::new(Vec::new())),
-    };
+    let figment = rocket::Config::figment()
+        .merge((
+            "limits",
+            Limits::default()
+                .limit("file", 10.gigabytes())
+                .limit("form", 10.gigabytes())
+                .limit("data-form", 10.gigabytes()),
+        ))
+        .merge(("temp_dir", _DATA_FOLDER)) // Use DATA_FOLDER to address upload volumes issue
+        .merge(("address", "0.0.0.0"))
+        .merge(("port", 9000));
+
+    // Set with ROCKET_MAX_PARALLEL_RUNS or max_parallel_runs in Rocket.toml
+    let max_parallel_runs: usize = figment
+        .extract_inner("max_parallel_runs")
+        .unwrap_or(_DEFAULT_MAX_PARALLEL_RUNS)
+        .max(1);
+    println!("Running up to {} analyses at once\n", max_parallel_runs);
+
+    let ttq = TimTicketQueue::new_instance(max_parallel_runs);
+    let tsec = TimSecrets::new_instance(&figment);
 
     let tsch = TimScheduler {
         should_run: Arc::new(Mutex::new(None)),
+        current_pass: Arc::new(Mutex::new(None)),
     };
 
-    // These three get moved into scheduler
-    let tq_1 = ttq.ticket_queue.clone();
-    let should_run_1 = tsch.should_run.clone();
+    let tr = TimRuns {
+        run_logs: Arc::new(Mutex::new(HashMap::new())),
+    };
+
+    // These four get moved into scheduler
+    let ttq_1 = ttq.clone();
+    let run_logs_1 = tr.run_logs.clone();
+    let tsec_1 = tsec.clone();
+    let tsch_1 = tsch.clone();
     let (scheduler_sender, mut scheduler_receiver) =
         tokio::sync::mpsc::channel::<SchedulerCommand>(16);
 
     rocket::tokio::spawn(async move {
         loop {
             let cmd = scheduler_receiver.recv().await.unwrap();
-            let mut should_run_lock = should_run_1.lock().unwrap();
+            // A task that panicked holding the lock mustn't stop the scheduler for good
+            let mut should_run_lock = tsch_1.should_run.lock().unwrap_or_else(|e| e.into_inner());
             if cmd == SchedulerCommand::Stop {
                 *should_run_lock = None;
+                // Runs are detached, so the one in progress has to be cancelled explicitly
+                let mut tq_2 = ttq_1.ticket_queue.clone();
+                let tickets: Vec<Ticket> = tq_2.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                for ticket in tickets.iter().filter(|x| x.started_by == _SCHEDULER_USER) {
+                    tq_2.cancel(&ticket.id);
+                }
                 continue;
             }
-            let run_id = Uuid::new_v4();
-            *should_run_lock = Some(run_id);
-
-            drop(should_run_lock);
-            let mut tq_2 = tq_1.clone();
-            let should_run_2 = should_run_1.clone();
-            let should_run_3 = should_run_1.clone();
-            let run_id_2 = run_id.clone();
-            let _ = rocket::tokio::spawn(async move {
-                let (sender_to_nowhere, mut recevier_to_nowhere) =
-                    tokio::sync::mpsc::channel::<RealTimeMessage>(65536);
-                let jh_recv = rocket::tokio::spawn(async move {
-                    while let Some(_) = recevier_to_nowhere.recv().await {
-                        let should_run_lock = should_run_2.lock().unwrap();
-                        match *should_run_lock {
-                            Some(v) => {
-                                if v != run_id_2 {
-                                    break;
-                                }
-                            }
-                            None => {
-                                break;
-                            }
+            // Only a manual start replaces the pass that is running
+            if should_run_lock.is_some() && matches!(cmd, SchedulerCommand::StartAnalyses { .. }) {
+                println!("A scheduler pass is running, so another one was not started");
+                continue;
+            }
+            let pass = match cmd {
+                SchedulerCommand::StartAnalyses {
+                    analysis_ids,
+                    trigger,
+                    force,
+                } => SchedulerPass::new(trigger, analysis_ids, force),
+                SchedulerCommand::Start { force } => {
+                    let analysis_ids = match get_list_of_analyses()
+                        .and_then(get_analysis_ids_for_scheduler_in_order)
+                    {
+                        Some(v) => v,
+                        None => {
+                            println!("Could not read the analyses, so the scheduler did not start");
+                            continue;
                         }
-                    }
-                });
-                let analyses = get_list_of_analyses().unwrap();
-                let mut analysis_ids = get_analysis_ids_for_scheduler_in_order(analyses).unwrap();
-                analysis_ids.reverse();
-                while let Some(analysis_id) = analysis_ids.pop() {
-                    let (temp_path, id) = tq_2.add();
-                    let end_status =
-                        match analyze_one_inner(&analysis_id, &sender_to_nowhere, &temp_path).await
-                        {
-                            Some(_) => StageResult::Success,
-                            None => StageResult::Failure,
-                        };
-                    let _ = update_metadata_after_run(
-                        &analysis_id,
-                        chrono::Utc::now(),
-                        &"Scheduler".to_string(),
-                        &end_status,
-                    );
-                    tq_2.remove(&id);
-                }
-                jh_recv.abort();
-                let mut should_run_lock = should_run_3.lock().unwrap();
-                if should_run_lock.is_none() {
-                    return;
-                }
-                if should_run_lock.unwrap() == run_id_2 {
-                    *should_run_lock = None;
+                    };
+                    SchedulerPass::new("Manual".to_string(), analysis_ids, force)
                 }
-            });
+                SchedulerCommand::Stop => continue,
+            };
+            *should_run_lock = Some(pass.id);
+
+            drop(should_run_lock);
+            let _ = rocket::tokio::spawn(run_scheduler_pass(
+                tsch_1.clone(),
+                ttq_1.clone(),
+                run_logs_1.clone(),
+                tsec_1.clone(),
+                pass,
+            ));
         }
     });
 
-    let figment = rocket::Config::figment()
-        .merge((
-            "limits",
-            Limits::default()
-                .limit("file", 10.gigabytes())
-                .limit("form", 10.gigabytes())
-                .limit("data-form", 10.gigabytes()),
-        ))
-        .merge(("temp_dir", _DATA_FOLDER)) // Use DATA_FOLDER to address upload volumes issue
-        .merge(("address", "0.0.0.0"))
-        .merge(("port", 9000));
+    let tschd = TimSchedules::new_instance();
+    rocket::tokio::spawn(run_schedules(
+        tschd.clone(),
+        tsch.should_run.clone(),
+        scheduler_sender.clone(),
+    ));
 
     let _ = rocket::custom(figment)
         .manage(scheduler_sender)
         .manage(tsm)
         .manage(ttq)
         .manage(tsch)
+        .manage(tr)
+        .manage(tsec)
+        .manage(tschd)
         // .manage(tsjh)
         .mount(
             "/api",
@@ -550,15 +665,22 @@ async fn main() {
                 delete_data_file,
                 //
                 get_analysis,
+                get_analysis_runs,
+                get_analysis_outputs,
+                get_analysis_output_versions,
                 create_analysis,
                 delete_analysis,
                 update_analysis,
                 run,
+                get_run,
+                run_events,
+                cancel_run,
                 get_queue,
                 //
                 check_file,
                 stream_private_file,
                 stream_public_file,
+                stream_output_version_file,
                 //
                 start_scheduler,
                 stop_scheduler,
@@ -566,6 +688,12 @@ async fn main() {
             ],
         )
         .mount("/api", user_routes())
+        .mount("/api", secret_routes())
+        .mount("/api", analysis_file_routes())
+        .mount("/api", library_routes())
+        .mount("/api", schedule_routes())
+        .mount("/api", scheduler_routes())
+        .mount("/api", validation_routes())
         .mount("/", FileServer::from(_HTML_FOLDER).rank(2))
         .attach(cors::CORS())
         .launch()
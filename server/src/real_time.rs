@@ -2,50 +2,125 @@ use super::*;
 
 pub async fn analyze_one_inner(
     analysis_id: &String,
+    run_id: &uuid::Uuid,
+    run_settings: &RunSettings,
     sender: &Sender<RealTimeMessage>,
     temp_path: &PathBuf,
-) -> Option<()> {
-    send_stage(Stage::InitializeAnalysis, StageResult::Pending, sender).ok()?;
+    cancel_receiver: &CancelReceiver,
+) -> StageResult {
+    // None means the listener went away before the analysis finished
+    analyze_stages(
+        analysis_id,
+        run_id,
+        run_settings,
+        sender,
+        temp_path,
+        cancel_receiver,
+    )
+    .await
+    .unwrap_or(StageResult::Failure)
+}
+
+async fn analyze_stages(
+    analysis_id: &String,
+    run_id: &uuid::Uuid,
+    run_settings: &RunSettings,
+    sender: &Sender<RealTimeMessage>,
+    temp_path: &PathBuf,
+    cancel_receiver: &CancelReceiver,
+) -> Option<StageResult> {
+    // Messages the run's channel can't take are only lost from the log (see run_with_pipes)
+    let _ = send_stage(Stage::InitializeAnalysis, StageResult::Pending, sender);
     let a = match get_analysis_package(analysis_id) {
         Some(v) => v,
         None => {
-            send_stage(Stage::InitializeAnalysis, StageResult::Failure, sender).ok()?;
-            return None;
+            let _ = send_stage(Stage::InitializeAnalysis, StageResult::Failure, sender);
+            return Some(StageResult::Failure);
         }
     };
 
-    send_stage(Stage::InitializeAnalysis, StageResult::Success, sender).ok()?;
-    send_stage(Stage::ImportInputFiles, StageResult::Pending, sender).ok()?;
-    let imported = import_files(temp_path, &a);
+    // None if an input can't be read, then the import below fails anyway
+    let fingerprint =
+        get_run_fingerprint(&a, &run_settings.parameters, run_settings.library_version);
+    let has_current_outputs =
+        a.metadata.outputs.is_empty() || get_current_output_version_number(&a.id).is_some();
+    if !run_settings.force
+        && fingerprint.is_some()
+        && fingerprint == a.metadata.last_success_fingerprint
+        && has_current_outputs
+    {
+        let _ = send_log_out(
+            "Up to date: script, inputs, parameters and library are unchanged since the last successful run"
+                .to_string(),
+            sender,
+        );
+        let _ = send_stage(Stage::InitializeAnalysis, StageResult::UpToDate, sender);
+        return Some(StageResult::UpToDate);
+    }
+
+    let _ = send_stage(Stage::InitializeAnalysis, StageResult::Success, sender);
+    let _ = send_stage(Stage::ImportInputFiles, StageResult::Pending, sender);
+    if let Some(library_version) = run_settings.library_version {
+        let _ = send_log_out(format!("Using library version {}", library_version), sender);
+    }
+    let imported = import_files(temp_path, &a, run_settings.library_version);
     if !imported {
-        send_stage(Stage::ImportInputFiles, StageResult::Failure, sender).ok()?;
-        return None;
+        let _ = send_stage(Stage::ImportInputFiles, StageResult::Failure, sender);
+        return Some(StageResult::Failure);
     }
-    send_stage(Stage::ImportInputFiles, StageResult::Success, sender).ok()?;
-    send_stage(Stage::CleanRun, StageResult::Pending, sender).ok()?;
+    let _ = send_stage(Stage::ImportInputFiles, StageResult::Success, sender);
+    let _ = send_stage(Stage::CleanRun, StageResult::Pending, sender);
 
-    let clean_run = if a.metadata.language == LanguageType::Stata {
-        run_stata(temp_path, sender).await
-    } else {
-        run_r(temp_path, sender).await
+    let registry = RunnerRegistry::load();
+    let clean_run = match registry.as_ref().and_then(|x| x.get(&a.metadata.language)) {
+        Some(runner) => {
+            runner
+                .run(
+                    temp_path,
+                    &a.metadata,
+                    &run_settings.parameters,
+                    &run_settings.secret_values,
+                    sender,
+                    cancel_receiver,
+                )
+                .await
+        }
+        None => {
+            let _ = send_log_err(
+                format!(
+                    "No runner set up for language \"{}\"",
+                    a.metadata.language.key()
+                ),
+                sender,
+            );
+            StageResult::Failure
+        }
     };
 
-    if clean_run.is_none() {
-        send_stage(Stage::CleanRun, StageResult::Failure, sender).ok()?;
-        return None;
+    if clean_run != StageResult::Success {
+        let _ = send_stage(Stage::CleanRun, clean_run.clone(), sender);
+        return Some(clean_run);
     }
-    send_stage(Stage::CleanRun, StageResult::Success, sender).ok()?;
-    send_stage(Stage::OutputFiles, StageResult::Pending, sender).ok()?;
-    let all_files_stored = store_outputs(temp_path, &a);
-    if !all_files_stored {
-        send_stage(Stage::OutputFiles, StageResult::Failure, sender).ok()?;
-        return None;
+    let _ = send_stage(Stage::CleanRun, StageResult::Success, sender);
+    let _ = send_stage(Stage::OutputFiles, StageResult::Pending, sender);
+    match store_outputs(temp_path, &a, run_id) {
+        Ok(version) => {
+            let _ = send_log_out(format!("Outputs stored as version {}", version), sender);
+        }
+        Err(error) => {
+            let _ = send_log_err(error, sender);
+            let _ = send_stage(Stage::OutputFiles, StageResult::Failure, sender);
+            return Some(StageResult::Failure);
+        }
     }
-    send_stage(Stage::OutputFiles, StageResult::Success, sender).ok()?;
-    Some(())
+    let _ = send_stage(Stage::OutputFiles, StageResult::Success, sender);
+    if let Some(fingerprint) = fingerprint {
+        let _ = update_metadata_fingerprint(&a.id, &fingerprint);
+    }
+    Some(StageResult::Success)
 }
 
-fn import_files(temp_path: &PathBuf, a: &AnalysisPackage) -> bool {
+fn import_files(temp_path: &PathBuf, a: &AnalysisPackage, library_version: Option<u64>) -> bool {
     // Script
     let code_file_path = temp_path.join(_FILE_NAME_MYSCRIPT);
     let res1 = write(code_file_path, format!("{}\r\n", &a.code));
@@ -53,10 +128,41 @@ fn import_files(temp_path: &PathBuf, a: &AnalysisPackage) -> bool {
         return false;
     }
 
+    // Other source files
+    let files_path = get_path_to_analysis_files(&a.id);
+    for file_name in get_analysis_file_names(&a.id) {
+        let to_path = temp_path.join(&file_name);
+        if let Some(parent) = to_path.parent() {
+            if create_dir_all(parent).is_err() {
+                return false;
+            }
+        }
+        if copy(files_path.join(&file_name), to_path).is_err() {
+            return false;
+        }
+    }
+
+    // Shared library
+    if let Some(library_version) = library_version {
+        let library_path = temp_path.join(_FOLDER_NAME_LIBRARY);
+        if copy_library_version(library_version, &library_path).is_none() {
+            return false;
+        }
+        // Also there when the library version is empty
+        if create_dir_all(library_path).is_err() {
+            return false;
+        }
+    }
+
     // Input files
     for input in &a.metadata.inputs {
         let fr_path = get_path_to_file(&input.folder_type, &input.analysis_id, &input.file_name);
         let to_path = temp_path.join(&input.file_name);
+        if let Some(parent) = to_path.parent() {
+            if create_dir_all(parent).is_err() {
+                return false;
+            }
+        }
         let res2 = copy(fr_path, to_path);
         if res2.is_err() {
             return false;
@@ -65,23 +171,61 @@ fn import_files(temp_path: &PathBuf, a: &AnalysisPackage) -> bool {
     true
 }
 
-fn store_outputs(temp_path: &PathBuf, a: &AnalysisPackage) -> bool {
-    let mut all_successful = true;
+// Nothing is published unless every output is there. The outputs become a new version, which
+// readers only see once it is complete (see promote_output_version).
+fn store_outputs(
+    temp_path: &PathBuf,
+    a: &AnalysisPackage,
+    run_id: &uuid::Uuid,
+) -> Result<u64, String> {
+    // Globs and folders only match files the run made, not the inputs and source files it was
+    // given (the other files import_files adds are hidden)
+    let imported_file_names: Vec<String> = a
+        .metadata
+        .inputs
+        .iter()
+        .map(|x| x.file_name.clone())
+        .chain(get_analysis_file_names(&a.id))
+        .collect();
+    let temp_file_names: Vec<String> = get_relative_file_paths(temp_path)
+        .into_iter()
+        .filter(|x| !imported_file_names.contains(x))
+        .collect();
+    let mut file_names: Vec<String> = Vec::new();
     for output in &a.metadata.outputs {
-        let fr_path = temp_path.join(&output.file_name);
-        if metadata(&fr_path).is_err() {
-            all_successful = false;
-            continue;
+        if !output.is_valid() {
+            return Err(format!(
+                "Output \"{}\" is not a valid path",
+                output.file_name
+            ));
         }
-        let to_path = PathBuf::from(_ANALYSES_FOLDER)
-            .join(&a.id)
-            .join(&output.file_name);
-        if copy(fr_path, to_path).is_err() {
-            all_successful = false;
+        if output.is_exact() {
+            if !temp_path.join(&output.file_name).is_file() {
+                return Err(format!("Output \"{}\" was not created", output.file_name));
+            }
+            file_names.push(output.file_name.clone());
             continue;
         }
+        let matched: Vec<&String> = temp_file_names
+            .iter()
+            .filter(|x| output.matches(x))
+            .collect();
+        // A glob or folder that matched nothing counts as a missing output
+        if matched.is_empty() {
+            return Err(format!("Output \"{}\" matched no files", output.file_name));
+        }
+        for file_name in matched {
+            if !file_names.contains(file_name) {
+                file_names.push(file_name.clone());
+            }
+        }
     }
-    all_successful
+    let keep_versions = a
+        .metadata
+        .keep_output_versions
+        .unwrap_or(_DEFAULT_KEEP_OUTPUT_VERSIONS);
+    promote_output_version(&a.id, run_id, temp_path, &file_names, keep_versions)
+        .ok_or_else(|| "Outputs could not be stored".to_string())
 }
 
 /////////////////////////////////////////////
@@ -96,9 +240,112 @@ fn send_stage(
 ) -> Result<(), tokio::sync::mpsc::error::TrySendError<RealTimeMessage>> {
     let rtm = RealTimeMessage {
         msg_type: MessageType::Stage,
+        run_id: None,
         stage: Some(stage),
         stage_result: Some(stage_result),
         log: None,
+        exit_code: None,
+        diagnostic: None,
+    };
+    sender.try_send(rtm)
+}
+
+pub fn send_log_out(
+    log: String,
+    sender: &Sender<RealTimeMessage>,
+) -> Result<(), tokio::sync::mpsc::error::TrySendError<RealTimeMessage>> {
+    let rtm = RealTimeMessage {
+        msg_type: MessageType::LogOut,
+        run_id: None,
+        stage: None,
+        stage_result: None,
+        log: Some(log),
+        exit_code: None,
+        diagnostic: None,
+    };
+    sender.try_send(rtm)
+}
+
+pub fn send_log_err(
+    log: String,
+    sender: &Sender<RealTimeMessage>,
+) -> Result<(), tokio::sync::mpsc::error::TrySendError<RealTimeMessage>> {
+    let rtm = RealTimeMessage {
+        msg_type: MessageType::LogErr,
+        run_id: None,
+        stage: None,
+        stage_result: None,
+        log: Some(log),
+        exit_code: None,
+        diagnostic: None,
+    };
+    sender.try_send(rtm)
+}
+
+// msg_type is ScriptError or ScriptWarning
+pub fn send_script_diagnostic(
+    msg_type: MessageType,
+    diagnostic: ScriptDiagnostic,
+    sender: &Sender<RealTimeMessage>,
+) -> Result<(), tokio::sync::mpsc::error::TrySendError<RealTimeMessage>> {
+    let mut log = match msg_type {
+        MessageType::ScriptWarning => format!("Warning: {}", diagnostic.summary()),
+        _ => format!("Error: {}", diagnostic.summary()),
+    };
+    for call in diagnostic.traceback.iter() {
+        log.push_str(&format!("\n    {}", call));
+    }
+    let rtm = RealTimeMessage {
+        msg_type,
+        run_id: None,
+        stage: None,
+        stage_result: None,
+        log: Some(log),
+        exit_code: None,
+        diagnostic: Some(Box::new(diagnostic)),
+    };
+    sender.try_send(rtm)
+}
+
+pub fn send_heartbeat(
+    sender: &Sender<RealTimeMessage>,
+) -> Result<(), tokio::sync::mpsc::error::TrySendError<RealTimeMessage>> {
+    let rtm = RealTimeMessage {
+        msg_type: MessageType::Heartbeat,
+        run_id: None,
+        stage: None,
+        stage_result: None,
+        log: None,
+        exit_code: None,
+        diagnostic: None,
+    };
+    sender.try_send(rtm)
+}
+
+// Resolves once the run is cancelled (never, if it isn't)
+pub async fn cancelled(mut cancel_receiver: CancelReceiver) {
+    loop {
+        if *cancel_receiver.borrow() {
+            return;
+        }
+        if cancel_receiver.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+pub fn send_exit(
+    exit_code: Option<i32>,
+    sender: &Sender<RealTimeMessage>,
+) -> Result<(), tokio::sync::mpsc::error::TrySendError<RealTimeMessage>> {
+    let rtm = RealTimeMessage {
+        msg_type: MessageType::Exit,
+        run_id: None,
+        stage: None,
+        stage_result: None,
+        log: None,
+        exit_code,
+        diagnostic: None,
     };
     sender.try_send(rtm)
 }
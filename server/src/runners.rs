@@ -0,0 +1,760 @@
+use super::*;
+
+// Runners are read from _RUNNERS_FILE_PATH, which holds a list like this...
+//
+// [
+//   {
+//     "language": "sas",
+//     "executable": "/usr/local/SASHome/SASFoundation/9.4/sas",
+//     "args": ["-sysin", "{script}", "-log", "run.log"],
+//     "logMode": { "type": "tailFile", "fileName": "run.log" },
+//     "success": { "type": "exitCode" },
+//     "maxParallelRuns": 2,
+//     "versionArgs": ["-version"]
+//   }
+// ]
+//
+// "{script}" in args is replaced by the script file name, and "{params}" by the analysis
+// parameter values (one arg each, in the order they are declared). Parameters are also set as
+// PARAM_<name> env vars, and the analysis secrets as env vars with their own names.
+// In a tailFile "fileName", "{scriptStem}" is replaced by the script file name without its
+// folder and extension, for interpreters that name the log after the script (Stata in batch
+// mode writes "main.log" for "main.do", and ".log" for ".script").
+// If the file does not exist, the built-in R, Stata and Python runners are used, which expect
+// Rscript and python3 on the PATH and Stata at /usr/local/stata16/stata-se. To use other
+// interpreter paths, put the runners in the file with the "executable" changed.
+//
+// "diagnostics" (optional) picks out the error from what the interpreter printed when a script
+// fails: { "type": "stataLog" } for Stata logs, { "type": "rWrapper" } to run R scripts through
+// a wrapper that reports warnings, the error and a traceback separately from the rest of stderr.
+//
+// If there is a shared library (see library.rs), the run's copy of it is set as
+// COMSA_LIBRARY_PATH. R sources all its R files at startup, Stata has it on the adopath and
+// Python on PYTHONPATH.
+//
+// The startup check looks for each executable and, if "versionArgs" is set, reports the first
+// line the interpreter prints when run with them. Stata can't print its version without running
+// a do-file, so the built-in Stata runner has no versionArgs and its version isn't reported.
+//
+// "maxParallelRuns" (optional) caps how many runs of that language go at once, e.g. for the
+// number of Stata license seats. Runs also count towards the overall max_parallel_runs.
+//
+// A runner can also have a "sandbox" entry, which runs the script inside bubblewrap (Linux only)
+// with no network (unless the analysis sets allowNetwork) and a filesystem that only has the
+// temp folder, the input files (read-only) and the readOnlyPaths...
+//
+//     "sandbox": {
+//       "bwrapPath": "/usr/bin/bwrap",
+//       "readOnlyPaths": ["/usr", "/lib", "/lib64", "/bin", "/etc/alternatives"]
+//     }
+
+#[rocket::async_trait]
+pub trait Runner: Send + Sync {
+    async fn run(
+        &self,
+        temp_path: &Path,
+        metadata: &AnalysisMetaData,
+        parameters: &[ParameterValue],
+        secret_values: &[SecretValue],
+        sender: &Sender<RealTimeMessage>,
+        cancel_receiver: &CancelReceiver,
+    ) -> StageResult;
+}
+
+enum ProcessEnd {
+    Exited(std::process::ExitStatus, String),
+    LimitExceeded,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunnerConfig {
+    pub language: LanguageType,
+    pub executable: String,
+    pub args: Vec<String>,
+    #[serde(rename = "logMode")]
+    pub log_mode: LogMode,
+    pub success: SuccessDetection,
+    #[serde(default)]
+    pub sandbox: Option<SandboxConfig>,
+    #[serde(rename = "maxParallelRuns", default)]
+    pub max_parallel_runs: Option<usize>,
+    #[serde(rename = "versionArgs", default)]
+    pub version_args: Option<Vec<String>>,
+    #[serde(default)]
+    pub diagnostics: Option<Diagnostics>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SandboxConfig {
+    #[serde(rename = "bwrapPath")]
+    pub bwrap_path: String,
+    #[serde(rename = "readOnlyPaths")]
+    pub read_only_paths: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum LogMode {
+    // Stream stdout as LogOut and stderr as LogErr
+    #[serde(rename = "pipe")]
+    Pipe,
+    // Stream the lines the interpreter writes to a log file in the temp folder
+    #[serde(rename = "tailFile")]
+    TailFile {
+        #[serde(rename = "fileName")]
+        file_name: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum SuccessDetection {
+    #[serde(rename = "exitCode")]
+    ExitCode,
+    // For interpreters (e.g. Stata) that exit cleanly even when the script fails
+    #[serde(rename = "exitCodeAndLogEndsWith")]
+    ExitCodeAndLogEndsWith { text: String },
+}
+
+pub struct RunnerRegistry {
+    pub runners: Vec<RunnerConfig>,
+}
+
+impl RunnerRegistry {
+    pub fn load() -> Option<RunnerRegistry> {
+        let runners_file_path = PathBuf::from(_RUNNERS_FILE_PATH);
+        if !runners_file_path.exists() {
+            return Some(RunnerRegistry {
+                runners: default_runner_configs(),
+            });
+        }
+        let runners_str = read_to_string(runners_file_path).ok()?;
+        let runners = serde_json::from_str(&runners_str).ok()?;
+        Some(RunnerRegistry { runners })
+    }
+
+    pub fn get(&self, language: &LanguageType) -> Option<&dyn Runner> {
+        let config = self.runners.iter().find(|x| &x.language == language)?;
+        Some(config)
+    }
+
+    pub fn get_max_parallel_runs(&self, language: &LanguageType) -> Option<usize> {
+        let config = self.runners.iter().find(|x| &x.language == language)?;
+        config.max_parallel_runs.map(|x| x.max(1))
+    }
+}
+
+pub fn default_runner_configs() -> Vec<RunnerConfig> {
+    vec![
+        RunnerConfig {
+            language: LanguageType::R,
+            executable: "Rscript".to_string(),
+            // Read with commandArgs(trailingOnly = TRUE)
+            args: vec!["{script}".to_string(), "{params}".to_string()],
+            log_mode: LogMode::Pipe,
+            success: SuccessDetection::ExitCode,
+            sandbox: None,
+            max_parallel_runs: None,
+            version_args: Some(vec!["--version".to_string()]),
+            diagnostics: Some(Diagnostics::RWrapper),
+        },
+        // Stata commandline info: https://www.stata.com/support/faqs/mac/advanced-topics/#startup
+        RunnerConfig {
+            language: LanguageType::Stata,
+            executable: "/usr/local/stata16/stata-se".to_string(),
+            args: vec![
+                "-e".to_string(),
+                "-q".to_string(),
+                "do".to_string(),
+                "{script}".to_string(),
+                // Read with "args" in the do-file
+                "{params}".to_string(),
+            ],
+            log_mode: LogMode::TailFile {
+                file_name: "{scriptStem}.log".to_string(),
+            },
+            success: SuccessDetection::ExitCodeAndLogEndsWith {
+                text: "end of do-file".to_string(),
+            },
+            sandbox: None,
+            max_parallel_runs: None,
+            // Stata has no version flag
+            version_args: None,
+            diagnostics: Some(Diagnostics::StataLog),
+        },
+        RunnerConfig {
+            language: LanguageType::Python,
+            executable: "python3".to_string(),
+            // "-u" keeps stdout/stderr unbuffered, otherwise print() output only arrives when the script ends
+            args: vec![
+                "-u".to_string(),
+                "{script}".to_string(),
+                "{params}".to_string(),
+            ],
+            log_mode: LogMode::Pipe,
+            success: SuccessDetection::ExitCode,
+            sandbox: None,
+            max_parallel_runs: None,
+            version_args: Some(vec!["--version".to_string()]),
+            diagnostics: None,
+        },
+    ]
+}
+
+#[rocket::async_trait]
+impl Runner for RunnerConfig {
+    async fn run(
+        &self,
+        temp_path: &Path,
+        metadata: &AnalysisMetaData,
+        parameters: &[ParameterValue],
+        secret_values: &[SecretValue],
+        sender: &Sender<RealTimeMessage>,
+        cancel_receiver: &CancelReceiver,
+    ) -> StageResult {
+        if self.find_executable().is_none() {
+            let _ = send_log_err(
+                format!(
+                    "Could not find \"{}\" to run {} scripts, check the runners file",
+                    self.executable,
+                    self.language.key()
+                ),
+                sender,
+            );
+            return StageResult::Failure;
+        }
+        let command = match self.command(temp_path, metadata, parameters, secret_values) {
+            Some(v) => v,
+            None => return StageResult::Failure,
+        };
+        let limits = &metadata.limits;
+        let process_end = match &self.log_mode {
+            LogMode::Pipe => {
+                self.run_with_pipes(command, limits, sender, cancel_receiver)
+                    .await
+            }
+            LogMode::TailFile { file_name } => {
+                let script_stem = get_script_stem(metadata.get_script_file_name());
+                let log_file_name = file_name.replace("{scriptStem}", script_stem);
+                let process_end = self
+                    .run_with_log_file(
+                        command,
+                        temp_path,
+                        &log_file_name,
+                        limits,
+                        sender,
+                        cancel_receiver,
+                    )
+                    .await;
+                // The log file stays in the workspace, where an output can pick it up, so it gets
+                // the same masking as the streamed log
+                if let Some(ProcessEnd::Exited(_, log)) = &process_end {
+                    let masked_log = mask_secrets(log, secret_values);
+                    if &masked_log != log
+                        && write(temp_path.join(&log_file_name), masked_log).is_err()
+                    {
+                        let _ = send_log_err(
+                            "Could not mask secrets in the log file".to_string(),
+                            sender,
+                        );
+                        return StageResult::Failure;
+                    }
+                }
+                process_end
+            }
+        };
+        let (exit_status, log) = match process_end {
+            Some(ProcessEnd::Exited(exit_status, log)) => (exit_status, log),
+            Some(ProcessEnd::LimitExceeded) => return StageResult::LimitExceeded,
+            Some(ProcessEnd::Cancelled) => return StageResult::Cancelled,
+            None => return StageResult::Failure,
+        };
+        let _ = send_exit(exit_status.code(), sender);
+        let result = if !exit_status.success() {
+            StageResult::Failure
+        } else {
+            match &self.success {
+                SuccessDetection::ExitCode => StageResult::Success,
+                SuccessDetection::ExitCodeAndLogEndsWith { text } => {
+                    if log.trim_end().ends_with(text.as_str()) {
+                        StageResult::Success
+                    } else {
+                        StageResult::Failure
+                    }
+                }
+            }
+        };
+        if result == StageResult::Failure {
+            if let Some(Diagnostics::StataLog) = &self.diagnostics {
+                let script = read_to_string(temp_path.join(metadata.get_script_file_name()))
+                    .unwrap_or_default();
+                if let Some(diagnostic) = get_stata_error(&log, &script) {
+                    let _ = send_script_diagnostic(MessageType::ScriptError, diagnostic, sender);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl RunnerConfig {
+    // A path, or a name looked up on the PATH
+    pub fn find_executable(&self) -> Option<PathBuf> {
+        if self.executable.contains('/') {
+            let path = PathBuf::from(&self.executable);
+            return if path.is_file() { Some(path) } else { None };
+        }
+        std::env::split_paths(&std::env::var_os("PATH")?)
+            .map(|x| x.join(&self.executable))
+            .find(|x| x.is_file())
+    }
+
+    // First line the interpreter prints with versionArgs, given 10 seconds
+    pub fn get_version(&self) -> Option<String> {
+        let version_args = self.version_args.as_ref()?;
+        let mut child = std::process::Command::new(&self.executable)
+            .args(version_args)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .ok()?;
+        for _ in 0..100 {
+            if child.try_wait().ok()?.is_some() {
+                let output = child.wait_with_output().ok()?;
+                // Some interpreters (e.g. Rscript) print the version to stderr
+                let text = format!(
+                    "{}\n{}",
+                    String::from_utf8_lossy(&output.stdout),
+                    String::from_utf8_lossy(&output.stderr)
+                );
+                return text
+                    .lines()
+                    .map(|x| x.trim())
+                    .find(|x| !x.is_empty())
+                    .map(|x| x.to_string());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        let _ = child.kill();
+        None
+    }
+
+    fn command(
+        &self,
+        temp_path: &Path,
+        metadata: &AnalysisMetaData,
+        parameters: &[ParameterValue],
+        secret_values: &[SecretValue],
+    ) -> Option<rocket::tokio::process::Command> {
+        let script_file_name = match &self.diagnostics {
+            Some(Diagnostics::RWrapper) => {
+                let wrapper = get_r_wrapper(metadata.get_script_file_name());
+                write(temp_path.join(_FILE_NAME_R_WRAPPER), wrapper).ok()?;
+                _FILE_NAME_R_WRAPPER
+            }
+            _ => metadata.get_script_file_name(),
+        };
+        let mut args: Vec<String> = Vec::new();
+        for arg in self.args.iter() {
+            if arg == "{params}" {
+                args.extend(parameters.iter().map(|x| x.value.clone()));
+            } else {
+                args.push(arg.replace("{script}", script_file_name));
+            }
+        }
+        let mut command = match &self.sandbox {
+            Some(sandbox) => sandbox.command(temp_path, metadata, &self.executable, &args)?,
+            None => {
+                let mut command = std::process::Command::new(&self.executable);
+                command.args(args);
+                command
+            }
+        };
+        // bwrap passes the env on to the script
+        for parameter in parameters.iter() {
+            command.env(format!("PARAM_{}", parameter.name), &parameter.value);
+        }
+        for secret_value in secret_values.iter() {
+            command.env(&secret_value.name, &secret_value.value);
+        }
+        let library_path = temp_path.join(_FOLDER_NAME_LIBRARY);
+        if library_path.exists() {
+            let library_path = library_path.canonicalize().ok()?;
+            for (name, value) in get_library_env(&metadata.language, temp_path, &library_path)? {
+                command.env(name, value);
+            }
+        }
+        // Own process group, so that cancelling can kill anything the script started too
+        command.current_dir(temp_path).process_group(0);
+        let mut command = rocket::tokio::process::Command::from(command);
+        command.kill_on_drop(true);
+        Some(command)
+    }
+
+    async fn run_with_pipes(
+        &self,
+        mut command: rocket::tokio::process::Command,
+        limits: &RunLimits,
+        sender: &Sender<RealTimeMessage>,
+        cancel_receiver: &CancelReceiver,
+    ) -> Option<ProcessEnd> {
+        // THE FOLLOWING CODE IS INSPIRED BY THIS... https://docs.rs/tokio/1.11.0/tokio/process/index.html
+        let child = command
+            .stdout(std::process::Stdio::piped()) // NOTE!!! We use stderr for "within" docker, stdout for "outside" docker
+            .stderr(std::process::Stdio::piped()) // NOTE!!! We use stderr for "within" docker, stdout for "outside" docker
+            .spawn();
+        let mut child = match child {
+            Ok(v) => v,
+            Err(e) => {
+                let _ = send_log_err(
+                    format!("Could not start \"{}\": {}", self.executable, e),
+                    sender,
+                );
+                return None;
+            }
+        };
+
+        let stdout = child.stdout.take()?;
+        let stderr = child.stderr.take()?;
+
+        let mut reader_stdout = tokio::io::BufReader::new(stdout);
+        let mut reader_stderr = tokio::io::BufReader::new(stderr);
+        let mut line_stdout = Vec::new();
+        let mut line_stderr = Vec::new();
+        let mut stdout_done = false;
+        let mut stderr_done = false;
+        let mut log = String::new();
+        let mut r_wrapper_reader = match &self.diagnostics {
+            Some(Diagnostics::RWrapper) => Some(RWrapperReader::default()),
+            _ => None,
+        };
+
+        let mut heartbeat_receiver = spawn_heartbeat();
+        let time_limit = sleep(Duration::from_secs(limits.timeout_seconds.unwrap_or(0)));
+        tokio::pin!(time_limit);
+        let cancel = cancelled(cancel_receiver.clone());
+        tokio::pin!(cancel);
+
+        // Returning early drops the child, which kills it (kill_on_drop). A message the run's
+        // channel can't take is only lost from the log, it doesn't end the run
+        let exit_status = loop {
+            tokio::select! {
+                msg = next_line_lossy(&mut reader_stdout, &mut line_stdout), if !stdout_done => {
+                    match msg.ok()? {
+                        Some(line) => {
+                            log.push_str(&line);
+                            log.push('\n');
+                            let _ = send_log_out(line, sender);
+                        },
+                        None => stdout_done = true,
+                    };
+                },
+                msg = next_line_lossy(&mut reader_stderr, &mut line_stderr), if !stderr_done => {
+                    match msg.ok()? {
+                        Some(line) => match r_wrapper_reader.as_mut() {
+                            Some(reader) if RWrapperReader::is_wrapper_line(&line) => {
+                                if let Some((msg_type, diagnostic)) = reader.read_line(&line) {
+                                    let _ = send_script_diagnostic(msg_type, diagnostic, sender);
+                                }
+                            }
+                            _ => {
+                                let _ = send_log_err(line, sender);
+                            }
+                        },
+                        None => stderr_done = true,
+                    };
+                },
+                exit_status = child.wait(), if stdout_done && stderr_done => {
+                    break exit_status.ok()?;
+                },
+                _ = &mut time_limit, if limits.timeout_seconds.is_some() => {
+                    return stop_child(child, ProcessEnd::LimitExceeded, time_limit_message(limits), sender).await;
+                },
+                _ = &mut cancel => {
+                    return stop_child(child, ProcessEnd::Cancelled, "Run cancelled".to_string(), sender).await;
+                },
+                _ = heartbeat_receiver.recv() => {
+                    let _ = send_heartbeat(sender);
+                    if let Some(msg) = get_exceeded_resource_limit(child.id(), limits) {
+                        return stop_child(child, ProcessEnd::LimitExceeded, msg, sender).await;
+                    }
+                }
+            }
+        };
+
+        if let Some(summary) = r_wrapper_reader.as_ref().and_then(|x| x.summary()) {
+            if r_wrapper_reader.as_ref().is_some_and(|x| x.failed()) {
+                let _ = send_log_err(summary, sender);
+            } else {
+                let _ = send_log_out(summary, sender);
+            }
+        }
+
+        Some(ProcessEnd::Exited(exit_status, log))
+    }
+
+    async fn run_with_log_file(
+        &self,
+        mut command: rocket::tokio::process::Command,
+        temp_path: &Path,
+        file_name: &str,
+        limits: &RunLimits,
+        sender: &Sender<RealTimeMessage>,
+        cancel_receiver: &CancelReceiver,
+    ) -> Option<ProcessEnd> {
+        let log_path = temp_path.join(file_name);
+        write(&log_path, "").ok()?;
+        let mut linemux_logfile_tailer = MuxedLines::new().ok()?;
+        linemux_logfile_tailer.add_file(&log_path).await.ok()?;
+
+        let mut child = match command.spawn() {
+            Ok(v) => v,
+            Err(e) => {
+                let _ = send_log_err(
+                    format!("Could not start \"{}\": {}", self.executable, e),
+                    sender,
+                );
+                return None;
+            }
+        };
+
+        let mut heartbeat_receiver = spawn_heartbeat();
+        let time_limit = sleep(Duration::from_secs(limits.timeout_seconds.unwrap_or(0)));
+        tokio::pin!(time_limit);
+        let cancel = cancelled(cancel_receiver.clone());
+        tokio::pin!(cancel);
+
+        // Returning early drops the child, which kills it (kill_on_drop). A message the run's
+        // channel can't take is only lost from the log, it doesn't end the run
+        let exit_status = loop {
+            tokio::select! {
+                msg = linemux_logfile_tailer.next_line() => {
+                    match msg {
+                        Ok(Some(line)) => {
+                            let _ = send_log_out(line.line().to_string(), sender);
+                        }
+                        Ok(None) => {}
+                        // A line that isn't UTF-8, the whole log is still read (lossily) below
+                        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                            let _ = send_log_err(format!("Could not read a line of the log: {}", e), sender);
+                        }
+                        Err(_) => return None,
+                    }
+                },
+                exit_status = child.wait() => {
+                    break exit_status.ok()?;
+                },
+                _ = &mut time_limit, if limits.timeout_seconds.is_some() => {
+                    return stop_child(child, ProcessEnd::LimitExceeded, time_limit_message(limits), sender).await;
+                },
+                _ = &mut cancel => {
+                    return stop_child(child, ProcessEnd::Cancelled, "Run cancelled".to_string(), sender).await;
+                },
+                _ = heartbeat_receiver.recv() => {
+                    let _ = send_heartbeat(sender);
+                    if let Some(msg) = get_exceeded_resource_limit(child.id(), limits) {
+                        return stop_child(child, ProcessEnd::LimitExceeded, msg, sender).await;
+                    }
+                }
+            }
+        };
+
+        // Pick up the lines written just before the process exited
+        loop {
+            match timeout(
+                Duration::from_millis(500),
+                linemux_logfile_tailer.next_line(),
+            )
+            .await
+            {
+                Ok(Ok(Some(line))) => {
+                    let _ = send_log_out(line.line().to_string(), sender);
+                }
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    let _ =
+                        send_log_err(format!("Could not read a line of the log: {}", e), sender);
+                }
+                _ => break,
+            }
+        }
+
+        let log = String::from_utf8_lossy(&read(log_path).ok()?).into_owned();
+        Some(ProcessEnd::Exited(exit_status, log))
+    }
+}
+
+impl SandboxConfig {
+    fn command(
+        &self,
+        temp_path: &Path,
+        metadata: &AnalysisMetaData,
+        executable: &str,
+        args: &[String],
+    ) -> Option<std::process::Command> {
+        // The workspace keeps its real path inside the sandbox, so log files etc. are where we expect them
+        let workspace = temp_path.canonicalize().ok()?;
+        let mut command = std::process::Command::new(&self.bwrap_path);
+        command.args(["--unshare-all", "--die-with-parent", "--new-session"]);
+        if metadata.allow_network {
+            command.arg("--share-net");
+        }
+        for p in &self.read_only_paths {
+            command.arg("--ro-bind-try").arg(p).arg(p);
+        }
+        command.args(["--proc", "/proc", "--dev", "/dev", "--tmpfs", "/tmp"]);
+        command.arg("--bind").arg(&workspace).arg(&workspace);
+        // Inputs are copies, but mount them read-only so scripts can't rely on changing them
+        for input in &metadata.inputs {
+            let input_path = workspace.join(&input.file_name);
+            command.arg("--ro-bind").arg(&input_path).arg(&input_path);
+        }
+        command.arg("--chdir").arg(&workspace);
+        command.arg("--").arg(executable).args(args);
+        Some(command)
+    }
+}
+
+// "sub/main.do" -> "main", ".script" -> ""
+// Like Lines::next_line, but output that isn't UTF-8 (e.g. Latin-1 from an old package) is decoded
+// lossily instead of being an error. Bytes read before select! drops the future stay in buf for the
+// next call, so no output is lost
+async fn next_line_lossy<R: tokio::io::AsyncBufRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> std::io::Result<Option<String>> {
+    reader.read_until(b'\n', buf).await?;
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf.ends_with(b"\n") {
+        buf.pop();
+        if buf.ends_with(b"\r") {
+            buf.pop();
+        }
+    }
+    let line = String::from_utf8_lossy(buf).into_owned();
+    buf.clear();
+    Ok(Some(line))
+}
+
+fn get_script_stem(script_file_name: &str) -> &str {
+    let base_name = script_file_name
+        .rsplit('/')
+        .next()
+        .unwrap_or(script_file_name);
+    base_name.rfind('.').map_or(base_name, |i| &base_name[..i])
+}
+
+async fn stop_child(
+    mut child: rocket::tokio::process::Child,
+    process_end: ProcessEnd,
+    msg: String,
+    sender: &Sender<RealTimeMessage>,
+) -> Option<ProcessEnd> {
+    if let Some(pid) = child.id() {
+        // The child leads its own process group (see command), so this kills the whole tree
+        unsafe {
+            libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+    let _ = child.kill().await;
+    let _ = send_log_err(msg, sender);
+    Some(process_end)
+}
+
+fn time_limit_message(limits: &RunLimits) -> String {
+    format!(
+        "Run stopped: exceeded the time limit of {} seconds",
+        limits.timeout_seconds.unwrap_or(0)
+    )
+}
+
+fn get_exceeded_resource_limit(pid: Option<u32>, limits: &RunLimits) -> Option<String> {
+    // Count the whole process tree, since the interpreter may be a child of a wrapper (e.g. bwrap)
+    let pids = get_process_tree(pid?);
+    if let Some(memory_mb) = limits.memory_mb {
+        let used: u64 = pids.iter().filter_map(|x| get_process_memory_mb(*x)).sum();
+        if used > memory_mb {
+            return Some(format!(
+                "Run stopped: exceeded the memory limit of {} MB",
+                memory_mb
+            ));
+        }
+    }
+    if let Some(cpu_seconds) = limits.cpu_seconds {
+        let used: u64 = pids.iter().filter_map(|x| get_process_cpu_ticks(*x)).sum();
+        // Clock ticks are 100 per second on Linux
+        if used / 100 > cpu_seconds {
+            return Some(format!(
+                "Run stopped: exceeded the CPU limit of {} seconds",
+                cpu_seconds
+            ));
+        }
+    }
+    None
+}
+
+// Memory and CPU are read from /proc, so they are only enforced on Linux
+
+fn get_process_tree(pid: u32) -> Vec<u32> {
+    let mut parents: Vec<(u32, u32)> = Vec::new();
+    if let Ok(entries) = read_dir("/proc") {
+        for entry in entries.flatten() {
+            let child_pid = match entry.file_name().to_str().and_then(|x| x.parse().ok()) {
+                Some(v) => v,
+                None => continue,
+            };
+            if let Some(parent_pid) = get_process_stat_field(child_pid, 1) {
+                parents.push((child_pid, parent_pid as u32));
+            }
+        }
+    }
+    let mut tree = vec![pid];
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        tree.extend(parents.iter().filter(|x| x.1 == parent).map(|x| x.0));
+        i += 1;
+    }
+    tree
+}
+
+fn get_process_memory_mb(pid: u32) -> Option<u64> {
+    let status = read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let line = status.lines().find(|x| x.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb / 1024)
+}
+
+fn get_process_cpu_ticks(pid: u32) -> Option<u64> {
+    // utime + stime, plus cutime + cstime for children that have already exited
+    let ticks = (11..15)
+        .filter_map(|x| get_process_stat_field(pid, x))
+        .sum();
+    Some(ticks)
+}
+
+fn get_process_stat_field(pid: u32, index_after_name: usize) -> Option<u64> {
+    let stat = read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The process name (2nd field) can contain spaces, so split after its closing bracket
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    fields.get(index_after_name)?.parse().ok()
+}
+
+fn spawn_heartbeat() -> tokio::sync::mpsc::Receiver<u8> {
+    let (heartbeat_sender, heartbeat_receiver) = tokio::sync::mpsc::channel::<u8>(600);
+    rocket::tokio::spawn(async move {
+        loop {
+            match heartbeat_sender.send(1).await {
+                Ok(_) => {}
+                Err(_) => {
+                    return;
+                }
+            }
+            sleep(Duration::from_millis(2000)).await;
+        }
+    });
+    heartbeat_receiver
+}
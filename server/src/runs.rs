@@ -0,0 +1,392 @@
+use super::*;
+
+// Runs are not tied to the HTTP connection that started them. Every message a run sends is kept
+// in its RunLog, so anyone who attaches (GET /runs/<run_id>/events) gets everything sent so far
+// and then follows along live.
+
+pub struct TimRuns {
+    pub run_logs: Arc<Mutex<HashMap<uuid::Uuid, RunLog>>>,
+}
+
+pub struct RunLog {
+    pub messages: Vec<RealTimeMessage>,
+    // Saved to the run history when the run opens and closes
+    pub record: RunRecord,
+    // None once the run has finished
+    broadcaster: Option<tokio::sync::broadcast::Sender<RealTimeMessage>>,
+}
+
+pub trait RunLogs {
+    fn open(
+        &mut self,
+        run_id: &uuid::Uuid,
+        analysis_id: &str,
+        started_by: &str,
+        parameters: &[ParameterValue],
+        library_version: Option<u64>,
+    );
+    fn push(&mut self, run_id: &uuid::Uuid, msg: RealTimeMessage);
+    fn close(&mut self, run_id: &uuid::Uuid);
+    fn subscribe(
+        &mut self,
+        run_id: &uuid::Uuid,
+    ) -> Option<(
+        Vec<RealTimeMessage>,
+        Option<tokio::sync::broadcast::Receiver<RealTimeMessage>>,
+    )>;
+    fn remove(&mut self, run_id: &uuid::Uuid);
+}
+
+impl RunLogs for Arc<Mutex<HashMap<uuid::Uuid, RunLog>>> {
+    fn open(
+        &mut self,
+        run_id: &uuid::Uuid,
+        analysis_id: &str,
+        started_by: &str,
+        parameters: &[ParameterValue],
+        library_version: Option<u64>,
+    ) {
+        let (broadcaster, _) = tokio::sync::broadcast::channel(65536);
+        let record = RunRecord {
+            run_id: *run_id,
+            analysis_id: analysis_id.to_string(),
+            started_at: chrono::Utc::now(),
+            ended_at: None,
+            started_by: started_by.to_string(),
+            status: StageResult::Pending,
+            stages: Vec::new(),
+            exit_code: None,
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            parameters: parameters.to_vec(),
+            library_version,
+            log: Vec::new(),
+        };
+        let _ = save_run_record(&record);
+        self.lock().expect("Should unlock").insert(
+            *run_id,
+            RunLog {
+                messages: Vec::new(),
+                record,
+                broadcaster: Some(broadcaster),
+            },
+        );
+    }
+    fn push(&mut self, run_id: &uuid::Uuid, msg: RealTimeMessage) {
+        let mut unlocked = self.lock().expect("Should unlock");
+        if let Some(run_log) = unlocked.get_mut(run_id) {
+            if let Some(broadcaster) = &run_log.broadcaster {
+                // Only fails if nobody is listening, which is fine
+                let _ = broadcaster.send(msg.clone());
+            }
+            update_run_record(&mut run_log.record, &msg);
+            // Heartbeats only keep live connections open, no point replaying them
+            if msg.msg_type != MessageType::Heartbeat {
+                run_log.messages.push(msg);
+            }
+        }
+    }
+    fn close(&mut self, run_id: &uuid::Uuid) {
+        let mut unlocked = self.lock().expect("Should unlock");
+        if let Some(run_log) = unlocked.get_mut(run_id) {
+            run_log.broadcaster = None;
+            run_log.record.ended_at = Some(chrono::Utc::now());
+            let _ = save_run_record(&run_log.record);
+        }
+    }
+    fn subscribe(
+        &mut self,
+        run_id: &uuid::Uuid,
+    ) -> Option<(
+        Vec<RealTimeMessage>,
+        Option<tokio::sync::broadcast::Receiver<RealTimeMessage>>,
+    )> {
+        // Copy and subscribe under the same lock, so no message is missed or sent twice
+        let unlocked = self.lock().expect("Should unlock");
+        let run_log = unlocked.get(run_id)?;
+        let receiver = run_log.broadcaster.as_ref().map(|x| x.subscribe());
+        Some((run_log.messages.clone(), receiver))
+    }
+    fn remove(&mut self, run_id: &uuid::Uuid) {
+        self.lock().expect("Should unlock").remove(run_id);
+    }
+}
+
+pub fn start_run(
+    ttq: TimTicketQueue,
+    mut run_logs: Arc<Mutex<HashMap<uuid::Uuid, RunLog>>>,
+    tsec: TimSecrets,
+    analysis_id: &str,
+    started_by: &str,
+    parameter_overrides: &HashMap<String, String>,
+    force: bool,
+) -> (uuid::Uuid, rocket::tokio::task::JoinHandle<StageResult>) {
+    let mut tq = ttq.ticket_queue.clone();
+    let (temp_path, id, cancel_receiver) = tq.add(analysis_id, started_by);
+    // Checked up front, so a bad value or missing secret fails the run straight away instead of after queueing
+    let (parameters, secret_values) = match get_metadata_from_analysis_id(&analysis_id.to_string())
+    {
+        Some(metadata) => (
+            metadata.resolve_parameters(parameter_overrides),
+            tsec.get_secret_values(analysis_id, &metadata.secrets),
+        ),
+        None => (Ok(Vec::new()), Ok(Vec::new())),
+    };
+    // The run uses this version even if the library changes while it waits
+    let library_version = get_current_library_version();
+    run_logs.open(
+        &id,
+        analysis_id,
+        started_by,
+        parameters.as_deref().unwrap_or_default(),
+        library_version,
+    );
+    let secret_values_1 = secret_values.clone().unwrap_or_default();
+
+    // Make this buffer BIG (e.g. 65536) because scripts can output a lot of log messages at once, causing it to fill up
+    let (sender, mut receiver) = tokio::sync::mpsc::channel::<RealTimeMessage>(65536);
+
+    // Move everything the run sends into its RunLog, until the run drops its sender
+    let mut run_logs_1 = run_logs.clone();
+    rocket::tokio::spawn(async move {
+        while let Some(mut msg) = receiver.recv().await {
+            // Before anything is stored or sent on, so secrets never reach a listener or the run history
+            msg.log = msg.log.map(|x| mask_secrets(&x, &secret_values_1));
+            if let Some(diagnostic) = msg.diagnostic.as_mut() {
+                mask_diagnostic_secrets(diagnostic, &secret_values_1);
+            }
+            run_logs_1.push(&id, msg);
+        }
+        run_logs_1.close(&id);
+        sleep(Duration::from_secs(_FINISHED_RUN_LOG_MINUTES * 60)).await;
+        run_logs_1.remove(&id);
+    });
+
+    let analysis_id = analysis_id.to_string();
+    let started_by = started_by.to_string();
+    let jh = rocket::tokio::spawn(async move {
+        let _ = sender.try_send(RealTimeMessage {
+            msg_type: MessageType::RunStarted,
+            run_id: Some(id),
+            stage: None,
+            stage_result: None,
+            log: None,
+            exit_code: None,
+            diagnostic: None,
+        });
+        let end_status = match (parameters, secret_values) {
+            (Err(error), _) | (_, Err(error)) => {
+                let _ = send_log_err(error, &sender);
+                StageResult::Failure
+            }
+            // The slots are given back when the permits are dropped at the end of the run
+            (Ok(parameters), Ok(secret_values)) => {
+                let run_settings = RunSettings {
+                    parameters,
+                    secret_values,
+                    force,
+                    library_version,
+                };
+                match wait_for_run_slots(&ttq, &id, &cancel_receiver, &sender).await {
+                    Some(_permits) => {
+                        tq.set_running(&id);
+                        ttq.queue_changed.notify_waiters();
+                        analyze_one_inner(
+                            &analysis_id,
+                            &id,
+                            &run_settings,
+                            &sender,
+                            &temp_path,
+                            &cancel_receiver,
+                        )
+                        .await
+                    }
+                    None => StageResult::Cancelled,
+                }
+            }
+        };
+        let msg_type = match end_status {
+            StageResult::Success | StageResult::UpToDate => MessageType::EndSuccess,
+            StageResult::Cancelled => MessageType::EndCancelled,
+            _ => MessageType::EndFailure,
+        };
+        let _ =
+            update_metadata_after_run(&analysis_id, chrono::Utc::now(), &started_by, &end_status);
+        let _ = sender.try_send(RealTimeMessage {
+            msg_type,
+            run_id: None,
+            stage: None,
+            stage_result: Some(end_status.clone()),
+            log: None,
+            exit_code: None,
+            diagnostic: None,
+        });
+        tq.remove(&id);
+        ttq.queue_changed.notify_waiters();
+        end_status
+    });
+
+    (id, jh)
+}
+
+// The language slot comes first, so runs waiting on a busy language don't hold up the
+// run slots other languages could use. None if the run was cancelled while waiting.
+async fn wait_for_run_slots(
+    ttq: &TimTicketQueue,
+    id: &uuid::Uuid,
+    cancel_receiver: &CancelReceiver,
+    sender: &Sender<RealTimeMessage>,
+) -> Option<Vec<tokio::sync::OwnedSemaphorePermit>> {
+    let mut permits = Vec::new();
+    let language = ttq.ticket_queue.clone().get(id)?.language;
+    let language_max = language.as_ref().and_then(|language| {
+        RunnerRegistry::load()?
+            .get_max_parallel_runs(language)
+            .map(|max| (language, max))
+    });
+    if let Some((language, max)) = language_max {
+        let slots = ttq.get_language_slots(language, max);
+        let resource = format!("{} slot (all {} in use)", language.key(), max);
+        let permit = wait_for_slot(ttq, slots, &resource, id, cancel_receiver, sender).await?;
+        permits.push(permit);
+    }
+    let resource = format!("run slot (all {} in use)", ttq.max_parallel_runs);
+    let permit = wait_for_slot(
+        ttq,
+        ttq.run_slots.clone(),
+        &resource,
+        id,
+        cancel_receiver,
+        sender,
+    )
+    .await?;
+    permits.push(permit);
+    Some(permits)
+}
+
+async fn wait_for_slot(
+    ttq: &TimTicketQueue,
+    slots: Arc<tokio::sync::Semaphore>,
+    resource: &str,
+    id: &uuid::Uuid,
+    cancel_receiver: &CancelReceiver,
+    sender: &Sender<RealTimeMessage>,
+) -> Option<tokio::sync::OwnedSemaphorePermit> {
+    if let Ok(permit) = slots.clone().try_acquire_owned() {
+        return Some(permit);
+    }
+    let mut tq = ttq.ticket_queue.clone();
+    tq.set_waiting(id, Some(slots.clone()));
+    let acquire = slots.acquire_owned();
+    tokio::pin!(acquire);
+    let cancel = cancelled(cancel_receiver.clone());
+    tokio::pin!(cancel);
+    let mut last_position = 0;
+    let permit = loop {
+        // Created before reading the position, so a change in between still wakes it
+        let queue_changed = ttq.queue_changed.notified();
+        tokio::pin!(queue_changed);
+        let position = tq.get_position(id);
+        if position != last_position {
+            last_position = position;
+            let _ = sender.try_send(RealTimeMessage {
+                msg_type: MessageType::Waiting,
+                run_id: None,
+                stage: None,
+                stage_result: None,
+                log: Some(format!(
+                    "Waiting for a free {}, position {} in the queue",
+                    resource, position
+                )),
+                exit_code: None,
+                diagnostic: None,
+            });
+        }
+        select! {
+            permit = &mut acquire => break permit.ok(),
+            _ = &mut cancel => break None,
+            _ = &mut queue_changed => {},
+        }
+    };
+    // The runs behind this one move up
+    tq.set_waiting(id, None);
+    ttq.queue_changed.notify_waiters();
+    permit
+}
+
+fn update_run_record(record: &mut RunRecord, msg: &RealTimeMessage) {
+    let now = chrono::Utc::now();
+    match msg.msg_type {
+        MessageType::Stage => {
+            let (stage, result) = match (&msg.stage, &msg.stage_result) {
+                (Some(stage), Some(result)) => (stage, result),
+                _ => return,
+            };
+            if result == &StageResult::Pending {
+                record.stages.push(StageRecord {
+                    stage: stage.clone(),
+                    result: result.clone(),
+                    started_at: now,
+                    ended_at: None,
+                });
+            } else if let Some(stage_record) = record.stages.iter_mut().find(|x| &x.stage == stage)
+            {
+                stage_record.result = result.clone();
+                stage_record.ended_at = Some(now);
+            }
+        }
+        MessageType::LogOut | MessageType::LogErr => record.log.push(msg.clone()),
+        MessageType::Exit => record.exit_code = msg.exit_code,
+        MessageType::ScriptError => {
+            if let Some(diagnostic) = &msg.diagnostic {
+                record.errors.push(*diagnostic.clone());
+            }
+        }
+        MessageType::ScriptWarning => {
+            if let Some(diagnostic) = &msg.diagnostic {
+                record.warnings.push(*diagnostic.clone());
+            }
+        }
+        MessageType::EndSuccess | MessageType::EndFailure | MessageType::EndCancelled => {
+            if let Some(result) = &msg.stage_result {
+                record.status = result.clone();
+            }
+        }
+        _ => {}
+    }
+}
+
+pub fn get_run_events(
+    mut run_logs: Arc<Mutex<HashMap<uuid::Uuid, RunLog>>>,
+    run_id: &uuid::Uuid,
+    mut end: Shutdown,
+) -> Option<EventStream![]> {
+    let (past_messages, receiver) = run_logs.subscribe(run_id)?;
+    Some(EventStream! {
+        for msg in past_messages {
+            yield Event::json(&msg);
+        }
+        if let Some(mut receiver) = receiver {
+            loop {
+                let msg = select! {
+                    msg = receiver.recv() => {
+                        match msg {
+                            Ok(msg) => msg,
+                            // This listener fell too far behind, skip what it missed
+                            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                            Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                                println!("Analysis process finished completely and sender closed.");
+                                break;
+                            },
+                        }
+                    },
+                    _ = &mut end => {
+                        println!("Server was shutdown");
+                        break;
+                    },
+                };
+                yield Event::json(&msg);
+            }
+        }
+    })
+}
@@ -0,0 +1,395 @@
+use super::*;
+use rocket::http::Status;
+use rocket::response::status::Custom;
+
+// A scheduler pass runs a list of analyses (started by hand, by a schedule, or for an analysis
+// and its dependencies) in dependency order. An analysis starts once the analyses it uses from
+// the same pass have succeeded, so independent ones run at the same time, up to
+// max_parallel_runs. Each pass is recorded in _SCHEDULER_PASSES_FOLDER as it goes, with the
+// result and duration of each analysis and the ones it skipped: those downstream of an analysis
+// that didn't succeed in the same pass, and the rest when the scheduler is stopped.
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SchedulerPass {
+    pub id: uuid::Uuid,
+    // "Manual", the name of the schedule, or what was asked for (see run_with_dependencies)
+    pub trigger: String,
+    #[serde(rename = "startedAt")]
+    pub started_at: DateTime<Utc>,
+    #[serde(rename = "endedAt")]
+    pub ended_at: Option<DateTime<Utc>>,
+    // In dependency order
+    #[serde(rename = "analysisIds")]
+    pub analysis_ids: Vec<String>,
+    pub results: Vec<SchedulerPassResult>,
+    pub skipped: Vec<SkippedAnalysis>,
+    // Stopped (or replaced by a new pass) before the end
+    pub stopped: bool,
+    // Runs every analysis, even those that are up to date
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SchedulerPassResult {
+    #[serde(rename = "analysisId")]
+    pub analysis_id: String,
+    #[serde(rename = "runId")]
+    pub run_id: uuid::Uuid,
+    // Pending while it runs
+    pub status: StageResult,
+    #[serde(rename = "startedAt")]
+    pub started_at: DateTime<Utc>,
+    #[serde(rename = "endedAt")]
+    pub ended_at: Option<DateTime<Utc>>,
+    #[serde(rename = "durationSeconds")]
+    pub duration_seconds: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SkippedAnalysis {
+    #[serde(rename = "analysisId")]
+    pub analysis_id: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Dependencies {
+    // The analyses that use its outputs, and the ones that use theirs, ...
+    #[serde(rename = "downstream")]
+    Downstream,
+    // The analyses whose outputs it uses, and the ones they use, ...
+    #[serde(rename = "upstream")]
+    Upstream,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunWithDependenciesRequest {
+    #[serde(rename = "analysisId")]
+    pub analysis_id: String,
+    pub dependencies: Dependencies,
+    // Runs them even if they are up to date
+    #[serde(default)]
+    pub force: bool,
+}
+
+impl SchedulerPass {
+    pub fn new(trigger: String, analysis_ids: Vec<String>, force: bool) -> SchedulerPass {
+        SchedulerPass {
+            id: Uuid::new_v4(),
+            trigger,
+            started_at: chrono::Utc::now(),
+            ended_at: None,
+            analysis_ids,
+            results: Vec::new(),
+            skipped: Vec::new(),
+            stopped: false,
+            force,
+        }
+    }
+
+    fn is_ok(&self, analysis_id: &String) -> bool {
+        self.results.iter().any(|x| {
+            &x.analysis_id == analysis_id
+                && (x.status == StageResult::Success || x.status == StageResult::UpToDate)
+        })
+    }
+
+    fn is_not_ok(&self, analysis_id: &String) -> bool {
+        self.skipped.iter().any(|x| &x.analysis_id == analysis_id)
+            || (self
+                .results
+                .iter()
+                .any(|x| &x.analysis_id == analysis_id && x.ended_at.is_some())
+                && !self.is_ok(analysis_id))
+    }
+}
+
+// Saved after every change, and shown as the current pass unless a newer one has started
+fn update_scheduler_pass(tsch: &TimScheduler, pass: &SchedulerPass) {
+    let _ = save_scheduler_pass(pass);
+    let mut current_pass = tsch.current_pass.lock().unwrap();
+    if current_pass.as_ref().is_some_and(|x| x.id == pass.id) {
+        *current_pass = Some(pass.clone());
+    }
+}
+
+pub async fn run_scheduler_pass(
+    tsch: TimScheduler,
+    ttq: TimTicketQueue,
+    run_logs: Arc<Mutex<HashMap<uuid::Uuid, RunLog>>>,
+    tsec: TimSecrets,
+    mut pass: SchedulerPass,
+) {
+    let pass_id = pass.id;
+    let analysis_ids = pass.analysis_ids.clone();
+    *tsch.current_pass.lock().unwrap() = Some(pass.clone());
+    let _ = save_scheduler_pass(&pass);
+
+    // Only upstream analyses in this pass are waited for
+    let upstream_ids: HashMap<String, Vec<String>> = analysis_ids
+        .iter()
+        .map(|analysis_id| {
+            let inputs =
+                get_metadata_from_analysis_id(analysis_id).map_or(Vec::new(), |x| x.inputs);
+            let ids = inputs
+                .into_iter()
+                .filter(|x| x.folder_type == FolderType::Analysis)
+                .map(|x| x.analysis_id)
+                .filter(|x| analysis_ids.contains(x))
+                .collect();
+            (analysis_id.clone(), ids)
+        })
+        .collect();
+    let mut waiting: Vec<String> = analysis_ids;
+    let mut running = 0;
+    // Each run sends its result here when it ends
+    let (ended_sender, mut ended_receiver) =
+        tokio::sync::mpsc::channel::<(uuid::Uuid, StageResult)>(ttq.max_parallel_runs);
+
+    loop {
+        if *tsch.should_run.lock().unwrap() != Some(pass_id) {
+            pass.stopped = true;
+        }
+        if pass.stopped && !waiting.is_empty() {
+            for analysis_id in waiting.drain(..) {
+                pass.skipped.push(SkippedAnalysis {
+                    analysis_id,
+                    reason: "Scheduler stopped".to_string(),
+                });
+            }
+            update_scheduler_pass(&tsch, &pass);
+        }
+
+        // Start what's ready, in order
+        let mut i = 0;
+        while i < waiting.len() && running < ttq.max_parallel_runs {
+            let analysis_id = waiting[i].clone();
+            let upstream_ids = &upstream_ids[&analysis_id];
+            if let Some(upstream_id) = upstream_ids.iter().find(|x| pass.is_not_ok(x)) {
+                let upstream_name = get_metadata_from_analysis_id(upstream_id)
+                    .map_or(upstream_id.clone(), |x| x.name);
+                pass.skipped.push(SkippedAnalysis {
+                    analysis_id,
+                    reason: format!("Upstream analysis \"{}\" did not succeed", upstream_name),
+                });
+                waiting.remove(i);
+                update_scheduler_pass(&tsch, &pass);
+                continue;
+            }
+            if !upstream_ids.iter().all(|x| pass.is_ok(x)) {
+                i += 1;
+                continue;
+            }
+            let (run_id, jh) = start_run(
+                ttq.clone(),
+                run_logs.clone(),
+                tsec.clone(),
+                &analysis_id,
+                _SCHEDULER_USER,
+                &HashMap::new(),
+                pass.force,
+            );
+            let ended_sender = ended_sender.clone();
+            rocket::tokio::spawn(async move {
+                let status = jh.await.unwrap_or(StageResult::Failure);
+                let _ = ended_sender.send((run_id, status)).await;
+            });
+            pass.results.push(SchedulerPassResult {
+                analysis_id,
+                run_id,
+                status: StageResult::Pending,
+                started_at: chrono::Utc::now(),
+                ended_at: None,
+                duration_seconds: None,
+            });
+            waiting.remove(i);
+            running += 1;
+            update_scheduler_pass(&tsch, &pass);
+        }
+
+        if running == 0 {
+            // Only left if they wait for each other, which get_analyses_in_order leaves out
+            for analysis_id in waiting.drain(..) {
+                pass.skipped.push(SkippedAnalysis {
+                    analysis_id,
+                    reason: "Dependency cycle".to_string(),
+                });
+            }
+            break;
+        }
+
+        let (run_id, status) = match ended_receiver.recv().await {
+            Some(v) => v,
+            None => break,
+        };
+        running -= 1;
+        let ended_at = chrono::Utc::now();
+        if let Some(result) = pass.results.iter_mut().find(|x| x.run_id == run_id) {
+            result.status = status;
+            result.ended_at = Some(ended_at);
+            result.duration_seconds =
+                Some((ended_at - result.started_at).num_milliseconds() as f64 / 1000.0);
+        }
+        update_scheduler_pass(&tsch, &pass);
+    }
+
+    pass.ended_at = Some(chrono::Utc::now());
+    update_scheduler_pass(&tsch, &pass);
+    let mut should_run = tsch.should_run.lock().unwrap();
+    if *should_run == Some(pass_id) {
+        *should_run = None;
+    }
+}
+
+// The analysis and all of its downstream (or upstream) analyses, in the order to run them
+pub fn get_analysis_ids_with_dependencies(
+    analyses: Vec<AnalysisSummary>,
+    analysis_id: &str,
+    dependencies: &Dependencies,
+) -> Vec<String> {
+    let mut related_ids: HashSet<String> = HashSet::new();
+    related_ids.insert(analysis_id.to_string());
+    loop {
+        let new_ids: Vec<String> = match dependencies {
+            Dependencies::Downstream => analyses
+                .iter()
+                .filter(|x| !related_ids.contains(&x.id))
+                .filter(|x| get_upstream_analysis_ids(x).any(|y| related_ids.contains(y)))
+                .map(|x| x.id.clone())
+                .collect(),
+            Dependencies::Upstream => analyses
+                .iter()
+                .filter(|x| related_ids.contains(&x.id))
+                .flat_map(get_upstream_analysis_ids)
+                .filter(|x| !related_ids.contains(*x))
+                .cloned()
+                .collect(),
+        };
+        if new_ids.is_empty() {
+            break;
+        }
+        related_ids.extend(new_ids);
+    }
+    get_analyses_in_order(analyses)
+        .into_iter()
+        .filter(|x| related_ids.contains(&x.id))
+        .map(|x| x.id)
+        .collect()
+}
+
+fn save_scheduler_pass(pass: &SchedulerPass) -> Option<()> {
+    let folder_path = PathBuf::from(_SCHEDULER_PASSES_FOLDER);
+    create_dir_all(&folder_path).ok()?;
+    let json_string = serde_json::to_string_pretty(pass).ok()?;
+    write(folder_path.join(format!("{}.json", pass.id)), json_string).ok()?;
+    Some(())
+}
+
+// Newest first
+fn get_scheduler_passes() -> Vec<SchedulerPass> {
+    let entries = match read_dir(_SCHEDULER_PASSES_FOLDER) {
+        Ok(v) => v,
+        Err(_) => return Vec::new(),
+    };
+    let mut passes: Vec<SchedulerPass> = entries
+        .flatten()
+        .filter_map(|x| read_to_string(x.path()).ok())
+        .filter_map(|x| serde_json::from_str(&x).ok())
+        .collect();
+    passes.sort_by_key(|x| std::cmp::Reverse(x.started_at));
+    passes
+}
+
+///////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////
+
+// The pass in progress, or the last one if none is running
+#[get("/scheduler/current")]
+fn current_scheduler_pass(
+    _user: UserWithRoles,
+    tsch: &State<TimScheduler>,
+) -> Json<Option<SchedulerPass>> {
+    let current_pass = tsch.current_pass.lock().unwrap().clone();
+    Json(current_pass.or_else(|| get_scheduler_passes().into_iter().next()))
+}
+
+#[get("/scheduler/passes?<limit>")]
+fn all_scheduler_passes(_user: UserWithRoles, limit: Option<usize>) -> Json<Vec<SchedulerPass>> {
+    let mut passes = get_scheduler_passes();
+    passes.truncate(limit.unwrap_or(50));
+    Json(passes)
+}
+
+#[get("/scheduler/passes/<pass_id>")]
+fn scheduler_pass(_user: UserWithRoles, pass_id: String) -> Option<Json<SchedulerPass>> {
+    let pass_id = Uuid::parse_str(&pass_id).ok()?;
+    let file_path = PathBuf::from(_SCHEDULER_PASSES_FOLDER).join(format!("{}.json", pass_id));
+    let pass = serde_json::from_str(&read_to_string(file_path).ok()?).ok()?;
+    Some(Json(pass))
+}
+
+// Starts a pass with the analysis and its downstream (or upstream) analyses, e.g. to rerun
+// everything that depends on a fixed cleaning script. Returns the analyses in the order they run.
+// Refused while another pass is running, like a schedule that comes due, so it can't stop it
+#[post("/run_with_dependencies", format = "application/json", data = "<rdr>")]
+async fn run_with_dependencies(
+    user: UserWithRoles,
+    rdr: Json<RunWithDependenciesRequest>,
+    tsch: &State<TimScheduler>,
+    scheduler_sender: &State<Sender<SchedulerCommand>>,
+) -> Result<Json<Vec<String>>, Custom<String>> {
+    if !user.can_edit {
+        return Err(Custom(Status::Forbidden, "Not allowed".to_string()));
+    }
+    let not_found = || Custom(Status::NotFound, "Analysis not found".to_string());
+    let m = get_metadata_from_analysis_id(&rdr.analysis_id).ok_or_else(not_found)?;
+    let analyses = get_list_of_analyses().ok_or_else(not_found)?;
+    let analysis_ids =
+        get_analysis_ids_with_dependencies(analyses, &rdr.analysis_id, &rdr.dependencies);
+    // Left out when it is part of a dependency cycle
+    if !analysis_ids.contains(&rdr.analysis_id) {
+        return Err(Custom(
+            Status::Conflict,
+            "The analysis is part of a dependency cycle".to_string(),
+        ));
+    }
+    if tsch.should_run.lock().unwrap().is_some() {
+        return Err(Custom(
+            Status::Conflict,
+            "Another scheduler pass is running".to_string(),
+        ));
+    }
+    let dependencies = match rdr.dependencies {
+        Dependencies::Downstream => "downstream",
+        Dependencies::Upstream => "upstream",
+    };
+    let trigger = format!(
+        "\"{}\" and its {} analyses, started by {}",
+        m.name, dependencies, user.email
+    );
+    scheduler_sender
+        .send(SchedulerCommand::StartAnalyses {
+            analysis_ids: analysis_ids.clone(),
+            trigger,
+            force: rdr.force,
+        })
+        .await
+        .map_err(|_| {
+            Custom(
+                Status::InternalServerError,
+                "Could not start the scheduler".to_string(),
+            )
+        })?;
+    Ok(Json(analysis_ids))
+}
+
+pub fn scheduler_routes() -> Vec<rocket::Route> {
+    routes![
+        current_scheduler_pass,
+        all_scheduler_passes,
+        scheduler_pass,
+        run_with_dependencies
+    ]
+}
@@ -0,0 +1,401 @@
+use super::*;
+use chrono::{Datelike, Local, NaiveDate, NaiveTime, TimeZone, Timelike, Weekday};
+
+// Schedules are kept in _SCHEDULES_FILE_PATH. Each one has a rule for when it's due...
+//
+//     { "type": "daily", "time": "02:30" }
+//     { "type": "weekly", "weekday": "Mon", "time": "06:00" }
+//     { "type": "cron", "expression": "0 2 * * 1-5" }
+//
+// (times are the server's local time, cron has the usual 5 fields: minute hour day-of-month
+// month day-of-week) and a target, the analyses it runs...
+//
+//     { "type": "analysis", "analysisId": "..." }
+//     { "type": "topic", "topic": "..." }
+//     { "type": "tag", "tag": "..." }
+//     { "type": "scheduled" }   (all analyses with "scheduled": true)
+//
+// A loop checks every _SCHEDULE_CHECK_SECONDS and starts a scheduler pass for each schedule
+// that is due, with the analyses in dependency order. There is one pass at a time, so a
+// schedule that comes due during another pass waits for it to finish. nextDueAt is saved, so
+// a time missed while the server was down is caught up when it starts.
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Schedule {
+    // Empty when creating one
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    pub rule: ScheduleRule,
+    pub target: ScheduleTarget,
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(rename = "nextDueAt", default)]
+    pub next_due_at: Option<DateTime<Utc>>,
+    #[serde(rename = "lastStartedAt", default)]
+    pub last_started_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum ScheduleRule {
+    #[serde(rename = "daily")]
+    Daily { time: String },
+    #[serde(rename = "weekly")]
+    Weekly { weekday: String, time: String },
+    #[serde(rename = "cron")]
+    Cron { expression: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum ScheduleTarget {
+    #[serde(rename = "analysis")]
+    Analysis {
+        #[serde(rename = "analysisId")]
+        analysis_id: String,
+    },
+    #[serde(rename = "topic")]
+    Topic { topic: String },
+    #[serde(rename = "tag")]
+    Tag { tag: String },
+    #[serde(rename = "scheduled")]
+    Scheduled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpcomingRun {
+    #[serde(rename = "scheduleId")]
+    pub schedule_id: String,
+    #[serde(rename = "scheduleName")]
+    pub schedule_name: String,
+    #[serde(rename = "dueAt")]
+    pub due_at: DateTime<Utc>,
+    #[serde(rename = "analysisIds")]
+    pub analysis_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteScheduleRequest {
+    pub id: String,
+}
+
+#[derive(Clone)]
+pub struct TimSchedules {
+    pub schedules: Arc<Mutex<Vec<Schedule>>>,
+}
+
+impl TimSchedules {
+    pub fn new_instance() -> TimSchedules {
+        let mut schedules = get_stored_schedules().unwrap_or_default();
+        // e.g. added to the file by hand
+        for schedule in schedules.iter_mut().filter(|x| x.next_due_at.is_none()) {
+            schedule.next_due_at = schedule.rule.next_after(Local::now());
+        }
+        let _ = save_stored_schedules(&schedules);
+        TimSchedules {
+            schedules: Arc::new(Mutex::new(schedules)),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////
+// Rules
+///////////////////////////////////////////////////////////
+
+// Parsed cron expression, each Vec says which values match
+struct CronExpression {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    // 0 is Sunday
+    days_of_week: Vec<bool>,
+    // As in cron, if both days are restricted either one can match
+    days_of_month_restricted: bool,
+    days_of_week_restricted: bool,
+}
+
+impl ScheduleRule {
+    fn get_cron_expression(&self) -> Option<CronExpression> {
+        match self {
+            ScheduleRule::Daily { time } => {
+                let time = NaiveTime::parse_from_str(time, "%H:%M").ok()?;
+                parse_cron_expression(&format!("{} {} * * *", time.minute(), time.hour()))
+            }
+            ScheduleRule::Weekly { weekday, time } => {
+                let weekday = weekday.parse::<Weekday>().ok()?;
+                let time = NaiveTime::parse_from_str(time, "%H:%M").ok()?;
+                parse_cron_expression(&format!(
+                    "{} {} * * {}",
+                    time.minute(),
+                    time.hour(),
+                    weekday.num_days_from_sunday()
+                ))
+            }
+            ScheduleRule::Cron { expression } => parse_cron_expression(expression),
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.get_cron_expression().is_some()
+    }
+
+    // The first time strictly after "after", None if there is none in the next few years
+    // (e.g. "0 0 31 2 *")
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Utc>> {
+        let cron_expression = self.get_cron_expression()?;
+        let start = after
+            .naive_local()
+            .date()
+            .and_hms(after.hour(), after.minute(), 0)
+            + chrono::Duration::minutes(1);
+        let end = start + chrono::Duration::days(4 * 366);
+        let mut t = start;
+        while t < end {
+            if !cron_expression.months[t.month() as usize] {
+                t = first_day_of_next_month(t.date())?.and_hms(0, 0, 0);
+            } else if !cron_expression.matches_day(t.date()) {
+                t = t.date().succ().and_hms(0, 0, 0);
+            } else if !cron_expression.hours[t.hour() as usize] {
+                t = t.date().and_hms(t.hour(), 0, 0) + chrono::Duration::hours(1);
+            } else if !cron_expression.minutes[t.minute() as usize] {
+                t += chrono::Duration::minutes(1);
+            } else {
+                // None when the clocks go forward past this time
+                match Local.from_local_datetime(&t).earliest() {
+                    Some(v) => return Some(v.with_timezone(&Utc)),
+                    None => t += chrono::Duration::minutes(1),
+                }
+            }
+        }
+        None
+    }
+}
+
+impl CronExpression {
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day_of_month = self.days_of_month[date.day() as usize];
+        let day_of_week = self.days_of_week[date.weekday().num_days_from_sunday() as usize];
+        match (self.days_of_month_restricted, self.days_of_week_restricted) {
+            (true, true) => day_of_month || day_of_week,
+            (true, false) => day_of_month,
+            (false, true) => day_of_week,
+            (false, false) => true,
+        }
+    }
+}
+
+fn first_day_of_next_month(date: NaiveDate) -> Option<NaiveDate> {
+    if date.month() == 12 {
+        NaiveDate::from_ymd_opt(date.year() + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(date.year(), date.month() + 1, 1)
+    }
+}
+
+fn parse_cron_expression(expression: &str) -> Option<CronExpression> {
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    if fields.len() != 5 {
+        return None;
+    }
+    let mut days_of_week = parse_cron_field(fields[4], 0, 7)?;
+    // 7 is Sunday too
+    if days_of_week[7] {
+        days_of_week[0] = true;
+    }
+    days_of_week.truncate(7);
+    Some(CronExpression {
+        minutes: parse_cron_field(fields[0], 0, 59)?,
+        hours: parse_cron_field(fields[1], 0, 23)?,
+        days_of_month: parse_cron_field(fields[2], 1, 31)?,
+        months: parse_cron_field(fields[3], 1, 12)?,
+        days_of_week,
+        days_of_month_restricted: fields[2] != "*",
+        days_of_week_restricted: fields[4] != "*",
+    })
+}
+
+// "*", "5", "1-5", "*/15", "0-30/10" and lists of them, e.g. "1,15,20-25"
+fn parse_cron_field(field: &str, min: usize, max: usize) -> Option<Vec<bool>> {
+    let mut matches = vec![false; max + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<usize>().ok().filter(|x| *x > 0)?),
+            None => (part, 1),
+        };
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else if let Some((from, to)) = range.split_once('-') {
+            (from.parse().ok()?, to.parse().ok()?)
+        } else {
+            let value = range.parse().ok()?;
+            // "5/10" means from 5 to the end
+            (value, if step > 1 { max } else { value })
+        };
+        if from < min || to > max || from > to {
+            return None;
+        }
+        for value in (from..=to).step_by(step) {
+            matches[value] = true;
+        }
+    }
+    Some(matches)
+}
+
+///////////////////////////////////////////////////////////
+// Running
+///////////////////////////////////////////////////////////
+
+// In dependency order
+pub fn get_schedule_analysis_ids(target: &ScheduleTarget) -> Option<Vec<String>> {
+    let analyses = get_list_of_analyses()?;
+    if target == &ScheduleTarget::Scheduled {
+        return get_analysis_ids_for_scheduler_in_order(analyses);
+    }
+    let analysis_ids = get_analyses_in_order(analyses)
+        .iter()
+        .filter(|x| match target {
+            ScheduleTarget::Analysis { analysis_id } => &x.id == analysis_id,
+            ScheduleTarget::Topic { topic } => &x.metadata.topic == topic,
+            ScheduleTarget::Tag { tag } => x.metadata.tags.contains(tag),
+            ScheduleTarget::Scheduled => x.metadata.scheduled,
+        })
+        .map(|x| x.id.clone())
+        .collect();
+    Some(analysis_ids)
+}
+
+pub async fn run_schedules(
+    tsch: TimSchedules,
+    should_run: Arc<Mutex<Option<Uuid>>>,
+    scheduler_sender: Sender<SchedulerCommand>,
+) {
+    loop {
+        sleep(Duration::from_secs(_SCHEDULE_CHECK_SECONDS)).await;
+        // One pass at a time
+        if should_run.lock().unwrap().is_some() {
+            continue;
+        }
+        let due_schedule = {
+            let mut schedules = tsch.schedules.lock().unwrap();
+            let now = Utc::now();
+            let due_schedule = schedules
+                .iter_mut()
+                .filter(|x| !x.paused && x.next_due_at.is_some_and(|d| d <= now))
+                .min_by_key(|x| x.next_due_at);
+            let due_schedule = match due_schedule {
+                Some(v) => {
+                    v.last_started_at = Some(now);
+                    v.next_due_at = v.rule.next_after(Local::now());
+                    v.clone()
+                }
+                None => continue,
+            };
+            let _ = save_stored_schedules(&schedules);
+            due_schedule
+        };
+        let analysis_ids = match get_schedule_analysis_ids(&due_schedule.target) {
+            Some(v) => v,
+            None => continue,
+        };
+        println!(
+            "Schedule \"{}\" is due, running {} analyses",
+            due_schedule.name,
+            analysis_ids.len()
+        );
+        let _ = scheduler_sender
+            .send(SchedulerCommand::StartAnalyses {
+                analysis_ids,
+                trigger: due_schedule.name.clone(),
+                force: false,
+            })
+            .await;
+    }
+}
+
+///////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////
+
+#[get("/schedules")]
+fn all_schedules(_user: UserWithRoles, tsch: &State<TimSchedules>) -> Json<Vec<Schedule>> {
+    let schedules = tsch.schedules.lock().unwrap();
+    Json(schedules.clone())
+}
+
+#[post("/set_schedule", format = "application/json", data = "<schedule>")]
+fn set_schedule(
+    user: UserWithRoles,
+    schedule: Json<Schedule>,
+    tsch: &State<TimSchedules>,
+) -> Option<Json<Vec<Schedule>>> {
+    if !user.can_edit || !schedule.rule.is_valid() {
+        return None;
+    }
+    let mut schedule = schedule.into_inner();
+    schedule.next_due_at = schedule.rule.next_after(Local::now());
+    let mut schedules = tsch.schedules.lock().unwrap();
+    if schedule.id.is_empty() {
+        schedule.id = Uuid::new_v4().to_string();
+        schedule.last_started_at = None;
+        schedules.push(schedule);
+    } else {
+        let existing = schedules.iter_mut().find(|x| x.id == schedule.id)?;
+        schedule.last_started_at = existing.last_started_at;
+        *existing = schedule;
+    }
+    save_stored_schedules(&schedules)?;
+    Some(Json(schedules.clone()))
+}
+
+#[post("/delete_schedule", format = "application/json", data = "<dsr>")]
+fn delete_schedule(
+    user: UserWithRoles,
+    dsr: Json<DeleteScheduleRequest>,
+    tsch: &State<TimSchedules>,
+) -> Option<Json<Vec<Schedule>>> {
+    if !user.can_edit {
+        return None;
+    }
+    let mut schedules = tsch.schedules.lock().unwrap();
+    schedules.retain(|x| x.id != dsr.id);
+    save_stored_schedules(&schedules)?;
+    Some(Json(schedules.clone()))
+}
+
+// Soonest first, over the next "days" (default 7)
+#[get("/schedules/upcoming?<days>")]
+fn upcoming_runs(
+    _user: UserWithRoles,
+    days: Option<i64>,
+    tsch: &State<TimSchedules>,
+) -> Json<Vec<UpcomingRun>> {
+    let schedules = tsch.schedules.lock().unwrap().clone();
+    let until = Utc::now() + chrono::Duration::days(days.unwrap_or(7).clamp(1, 366));
+    let mut upcoming_runs = Vec::new();
+    for schedule in schedules.iter().filter(|x| !x.paused) {
+        let analysis_ids = get_schedule_analysis_ids(&schedule.target).unwrap_or_default();
+        let mut due_at = schedule.next_due_at;
+        // Enough for every minute of a day
+        for _ in 0..1440 {
+            let d = match due_at {
+                Some(v) if v <= until => v,
+                _ => break,
+            };
+            upcoming_runs.push(UpcomingRun {
+                schedule_id: schedule.id.clone(),
+                schedule_name: schedule.name.clone(),
+                due_at: d,
+                analysis_ids: analysis_ids.clone(),
+            });
+            due_at = schedule.rule.next_after(d.with_timezone(&Local));
+        }
+    }
+    upcoming_runs.sort_by_key(|x| x.due_at);
+    Json(upcoming_runs)
+}
+
+pub fn schedule_routes() -> Vec<rocket::Route> {
+    routes![all_schedules, set_schedule, delete_schedule, upcoming_runs]
+}
@@ -0,0 +1,256 @@
+use super::*;
+use rocket::http::private::cookie::Key;
+
+// Secrets (database passwords, API keys, ...) are kept in _SECRETS_FILE_PATH, encrypted with the
+// Rocket secret_key, the same way as private cookies (AES-256-GCM, with the secret name bound in).
+// Only admins can set or delete them, and the values can't be read back through the API.
+//
+// An analysis lists the names of the secrets it needs (metadata "secrets"). Each one is set as
+// an env var with the same name when the script runs, and its value is masked in the run log.
+// Editors can list any name, so admins also grant each secret to the analyses that may use it,
+// and a run of any other analysis fails.
+
+#[derive(Clone)]
+pub struct TimSecrets {
+    // None if no secret_key is set (in debug Rocket makes a new one on every launch, so
+    // anything encrypted with it would be lost on restart)
+    key: Option<Arc<Key>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredSecret {
+    pub name: String,
+    #[serde(rename = "encryptedValue")]
+    pub encrypted_value: String,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: DateTime<Utc>,
+    #[serde(rename = "updatedBy")]
+    pub updated_by: String,
+    // The analyses allowed to use it
+    #[serde(rename = "analysisIds", default)]
+    pub analysis_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SecretSummary {
+    pub name: String,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: DateTime<Utc>,
+    #[serde(rename = "updatedBy")]
+    pub updated_by: String,
+    #[serde(rename = "analysisIds")]
+    pub analysis_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetSecretRequest {
+    pub name: String,
+    pub value: String,
+}
+
+// Replaces the analyses the secret is granted to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GrantSecretRequest {
+    pub name: String,
+    #[serde(rename = "analysisIds")]
+    pub analysis_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteSecretRequest {
+    pub name: String,
+}
+
+// Decrypted, only ever kept in memory for the run that needs it
+#[derive(Debug, Clone)]
+pub struct SecretValue {
+    pub name: String,
+    pub value: String,
+}
+
+impl TimSecrets {
+    pub fn new_instance(figment: &rocket::figment::Figment) -> TimSecrets {
+        TimSecrets {
+            key: get_secret_key(figment).map(Arc::new),
+        }
+    }
+
+    fn encrypt(&self, name: &str, value: &str) -> Option<String> {
+        let key = self.key.as_ref()?;
+        let mut jar = rocket::http::private::cookie::CookieJar::new();
+        jar.private_mut(key)
+            .add(Cookie::new(name.to_string(), value.to_string()));
+        Some(jar.get(name)?.value().to_string())
+    }
+
+    fn decrypt(&self, name: &str, encrypted_value: &str) -> Option<String> {
+        let key = self.key.as_ref()?;
+        let jar = rocket::http::private::cookie::CookieJar::new();
+        let cookie = jar
+            .private(key)
+            .decrypt(Cookie::new(name.to_string(), encrypted_value.to_string()))?;
+        Some(cookie.value().to_string())
+    }
+
+    pub fn get_secret_values(
+        &self,
+        analysis_id: &str,
+        names: &[String],
+    ) -> Result<Vec<SecretValue>, String> {
+        if names.is_empty() {
+            return Ok(Vec::new());
+        }
+        if self.key.is_none() {
+            return Err("Secrets can't be used because the server has no secret_key".to_string());
+        }
+        let stored_secrets = get_stored_secrets().ok_or("Could not read secrets file")?;
+        let mut secret_values = Vec::new();
+        for name in names.iter() {
+            let stored_secret = stored_secrets
+                .iter()
+                .find(|x| &x.name == name)
+                .ok_or(format!("Secret \"{}\" is not set", name))?;
+            if !stored_secret.analysis_ids.iter().any(|x| x == analysis_id) {
+                return Err(format!(
+                    "Secret \"{}\" is not granted to this analysis",
+                    name
+                ));
+            }
+            let value = self
+                .decrypt(name, &stored_secret.encrypted_value)
+                .ok_or(format!("Secret \"{}\" could not be decrypted", name))?;
+            secret_values.push(SecretValue {
+                name: name.clone(),
+                value,
+            });
+        }
+        Ok(secret_values)
+    }
+}
+
+// Same formats Rocket accepts: base64 (44 or 88 chars) or hex (64 chars)
+fn get_secret_key(figment: &rocket::figment::Figment) -> Option<Key> {
+    let secret_key: String = figment.extract_inner("secret_key").ok()?;
+    let bytes = match secret_key.len() {
+        44 | 88 => base64::decode(&secret_key).ok()?,
+        64 => (0..64)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(secret_key.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?,
+        _ => return None,
+    };
+    if bytes.len() >= 64 {
+        Some(Key::from(&bytes))
+    } else if bytes.len() >= 32 {
+        Some(Key::derive_from(&bytes))
+    } else {
+        None
+    }
+}
+
+pub fn mask_secrets(log: &str, secret_values: &[SecretValue]) -> String {
+    let mut masked = log.to_string();
+    for secret_value in secret_values.iter().filter(|x| !x.value.is_empty()) {
+        masked = masked.replace(&secret_value.value, "********");
+    }
+    masked
+}
+
+// Script errors and warnings can quote the value too (e.g. a connection string)
+pub fn mask_diagnostic_secrets(diagnostic: &mut ScriptDiagnostic, secret_values: &[SecretValue]) {
+    diagnostic.message = mask_secrets(&diagnostic.message, secret_values);
+    diagnostic.command = diagnostic
+        .command
+        .as_ref()
+        .map(|x| mask_secrets(x, secret_values));
+    for call in diagnostic.traceback.iter_mut() {
+        *call = mask_secrets(call, secret_values);
+    }
+}
+
+// Also used for env var names, so the same rules
+fn is_valid_secret_name(name: &str) -> bool {
+    name.chars()
+        .next()
+        .is_some_and(|x| x.is_ascii_alphabetic() || x == '_')
+        && name.chars().all(|x| x.is_ascii_alphanumeric() || x == '_')
+}
+
+///////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////
+
+#[get("/secrets")]
+fn all_secrets(user: UserWithRoles) -> Json<Vec<SecretSummary>> {
+    if !user.is_admin {
+        return Json(Vec::new());
+    }
+    let stored_secrets = get_stored_secrets().unwrap_or_default();
+    let secret_summaries: Vec<SecretSummary> = stored_secrets
+        .iter()
+        .map(|x| SecretSummary {
+            name: x.name.clone(),
+            updated_at: x.updated_at,
+            updated_by: x.updated_by.clone(),
+            analysis_ids: x.analysis_ids.clone(),
+        })
+        .collect();
+    Json(secret_summaries)
+}
+
+#[post("/set_secret", format = "application/json", data = "<ssr>")]
+fn set_secret(
+    user: UserWithRoles,
+    ssr: Json<SetSecretRequest>,
+    tsec: &State<TimSecrets>,
+) -> Option<()> {
+    if !user.is_admin || !is_valid_secret_name(&ssr.name) {
+        return None;
+    }
+    let encrypted_value = tsec.encrypt(&ssr.name, &ssr.value)?;
+    let mut stored_secrets = get_stored_secrets()?;
+    // A new value keeps the grants
+    let analysis_ids = stored_secrets
+        .iter()
+        .find(|x| x.name == ssr.name)
+        .map_or(Vec::new(), |x| x.analysis_ids.clone());
+    stored_secrets.retain(|x| x.name != ssr.name);
+    stored_secrets.push(StoredSecret {
+        name: ssr.name.clone(),
+        encrypted_value,
+        updated_at: chrono::Utc::now(),
+        updated_by: user.email.clone(),
+        analysis_ids,
+    });
+    save_stored_secrets(stored_secrets)?;
+    Some(())
+}
+
+#[post("/grant_secret", format = "application/json", data = "<gsr>")]
+fn grant_secret(user: UserWithRoles, gsr: Json<GrantSecretRequest>) -> Option<()> {
+    if !user.is_admin {
+        return None;
+    }
+    let mut stored_secrets = get_stored_secrets()?;
+    let stored_secret = stored_secrets.iter_mut().find(|x| x.name == gsr.name)?;
+    stored_secret.analysis_ids = gsr.analysis_ids.clone();
+    stored_secret.updated_at = chrono::Utc::now();
+    stored_secret.updated_by = user.email.clone();
+    save_stored_secrets(stored_secrets)?;
+    Some(())
+}
+
+#[post("/delete_secret", format = "application/json", data = "<dsr>")]
+fn delete_secret(user: UserWithRoles, dsr: Json<DeleteSecretRequest>) -> Option<()> {
+    if !user.is_admin {
+        return None;
+    }
+    let mut stored_secrets = get_stored_secrets()?;
+    stored_secrets.retain(|x| x.name != dsr.name);
+    save_stored_secrets(stored_secrets)?;
+    Some(())
+}
+
+pub fn secret_routes() -> Vec<rocket::Route> {
+    routes![all_secrets, set_secret, grant_secret, delete_secret]
+}
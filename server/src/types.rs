@@ -1,53 +1,176 @@
 use super::*;
 
+// Every run (interactive or scheduled) takes a ticket, then waits for a slot for its language
+// (if the runner sets maxParallelRuns) and then for one of the run slots.
+// The semaphores hand out slots in the order runs started waiting.
+#[derive(Clone)]
 pub struct TimTicketQueue {
     pub ticket_queue: Arc<Mutex<Vec<Ticket>>>,
+    pub run_slots: Arc<tokio::sync::Semaphore>,
+    pub max_parallel_runs: usize,
+    // Language key -> slots for runners that set maxParallelRuns
+    pub language_slots: Arc<Mutex<HashMap<String, LanguageSlots>>>,
+    // Notified when a ticket gets a slot, starts running or is removed, so waiting runs can update
+    // their position
+    pub queue_changed: Arc<tokio::sync::Notify>,
 }
 
+pub struct LanguageSlots {
+    pub max_parallel_runs: usize,
+    pub slots: Arc<tokio::sync::Semaphore>,
+}
+
+impl TimTicketQueue {
+    pub fn new_instance(max_parallel_runs: usize) -> TimTicketQueue {
+        TimTicketQueue {
+            ticket_queue: Arc::new(Mutex::new(Vec::new())),
+            run_slots: Arc::new(tokio::sync::Semaphore::new(max_parallel_runs)),
+            max_parallel_runs,
+            language_slots: Arc::new(Mutex::new(HashMap::new())),
+            queue_changed: Arc::new(tokio::sync::Notify::new()),
+        }
+    }
+
+    pub fn get_language_slots(
+        &self,
+        language: &LanguageType,
+        max_parallel_runs: usize,
+    ) -> Arc<tokio::sync::Semaphore> {
+        let mut unlocked = self.language_slots.lock().expect("Should unlock");
+        let entry = unlocked
+            .entry(language.key().to_string())
+            .or_insert_with(|| LanguageSlots {
+                max_parallel_runs,
+                slots: Arc::new(tokio::sync::Semaphore::new(max_parallel_runs)),
+            });
+        // The runners file changed, runs holding slots from the old semaphore just finish as normal
+        if entry.max_parallel_runs != max_parallel_runs {
+            *entry = LanguageSlots {
+                max_parallel_runs,
+                slots: Arc::new(tokio::sync::Semaphore::new(max_parallel_runs)),
+            };
+        }
+        entry.slots.clone()
+    }
+}
+
+#[derive(Clone)]
 pub struct TimScheduler {
+    // Id of the pass that should keep going, None when stopped
     pub should_run: Arc<Mutex<Option<Uuid>>>,
+    pub current_pass: Arc<Mutex<Option<SchedulerPass>>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum SchedulerCommand {
-    Start,
+    // All analyses with "scheduled": true. force runs them even if they are up to date
+    Start {
+        force: bool,
+    },
+    // These, in this order (from a schedule, or see run_with_dependencies). Ignored while a pass
+    // is running
+    StartAnalyses {
+        analysis_ids: Vec<String>,
+        trigger: String,
+        force: bool,
+    },
     Stop,
 }
 
 pub trait TicketQueue {
-    fn add(&mut self) -> (PathBuf, uuid::Uuid);
+    fn add(&mut self, analysis_id: &str, started_by: &str)
+        -> (PathBuf, uuid::Uuid, CancelReceiver);
+    fn get(&mut self, id: &uuid::Uuid) -> Option<Ticket>;
     fn get_position(&mut self, id: &uuid::Uuid) -> usize;
+    fn set_waiting(&mut self, id: &uuid::Uuid, slots: Option<Arc<tokio::sync::Semaphore>>);
+    fn set_running(&mut self, id: &uuid::Uuid);
+    fn cancel(&mut self, id: &uuid::Uuid) -> Option<()>;
     fn remove(&mut self, id: &uuid::Uuid);
 }
 
+// The ticket id is also the run id
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Ticket {
     pub id: uuid::Uuid,
     pub temp_path: PathBuf,
     pub date: DateTime<Utc>,
+    #[serde(rename = "analysisId")]
+    pub analysis_id: String,
+    #[serde(rename = "startedBy")]
+    pub started_by: String,
+    pub language: Option<LanguageType>,
+    pub running: bool,
+    // The semaphore the run is waiting on for a slot, and since when
+    #[serde(skip)]
+    pub waiting_for: Option<(Arc<tokio::sync::Semaphore>, DateTime<Utc>)>,
+    #[serde(skip)]
+    pub cancel_sender: Option<Arc<tokio::sync::watch::Sender<bool>>>,
 }
 
+pub type CancelReceiver = tokio::sync::watch::Receiver<bool>;
+
 impl TicketQueue for Arc<Mutex<Vec<Ticket>>> {
-    fn add(&mut self) -> (PathBuf, uuid::Uuid) {
+    fn add(
+        &mut self,
+        analysis_id: &str,
+        started_by: &str,
+    ) -> (PathBuf, uuid::Uuid, CancelReceiver) {
         let (temp_path, id) = get_new_temp_path();
+        let (cancel_sender, cancel_receiver) = tokio::sync::watch::channel(false);
         self.lock().expect("Should unlock").push(Ticket {
-            id: id.clone(),
+            id,
             temp_path: temp_path.clone(),
             date: chrono::Utc::now(),
+            analysis_id: analysis_id.to_string(),
+            started_by: started_by.to_string(),
+            language: get_metadata_from_analysis_id(&analysis_id.to_string()).map(|x| x.language),
+            running: false,
+            waiting_for: None,
+            cancel_sender: Some(Arc::new(cancel_sender)),
         });
-        (temp_path, id)
+        (temp_path, id, cancel_receiver)
     }
-    fn get_position(&mut self, id: &uuid::Uuid) -> usize {
-        match self
-            .lock()
+    fn get(&mut self, id: &uuid::Uuid) -> Option<Ticket> {
+        self.lock()
             .expect("Should unlock")
             .iter()
-            .position(|x| x.id == *id)
-        {
-            Some(index) => index,
-            None => 0,
+            .find(|x| x.id == *id)
+            .cloned()
+    }
+    fn get_position(&mut self, id: &uuid::Uuid) -> usize {
+        // 1 means next in line, 0 means not waiting (or unknown)
+        // Only counts runs waiting on the same semaphore, which hands out slots in the order they
+        // started waiting
+        let unlocked = self.lock().expect("Should unlock");
+        let (slots, since) = match unlocked.iter().find(|x| x.id == *id) {
+            Some(Ticket {
+                waiting_for: Some(v),
+                ..
+            }) => v,
+            _ => return 0,
+        };
+        unlocked
+            .iter()
+            .filter_map(|x| x.waiting_for.as_ref())
+            .filter(|(x_slots, x_since)| Arc::ptr_eq(x_slots, slots) && x_since <= since)
+            .count()
+    }
+    fn set_waiting(&mut self, id: &uuid::Uuid, slots: Option<Arc<tokio::sync::Semaphore>>) {
+        let mut unlocked = self.lock().expect("Should unlock");
+        if let Some(ticket) = unlocked.iter_mut().find(|x| x.id == *id) {
+            ticket.waiting_for = slots.map(|x| (x, chrono::Utc::now()));
         }
     }
+    fn set_running(&mut self, id: &uuid::Uuid) {
+        let mut unlocked = self.lock().expect("Should unlock");
+        if let Some(ticket) = unlocked.iter_mut().find(|x| x.id == *id) {
+            ticket.running = true;
+        }
+    }
+    fn cancel(&mut self, id: &uuid::Uuid) -> Option<()> {
+        let ticket = self.get(id)?;
+        ticket.cancel_sender?.send(true).ok()
+    }
     fn remove(&mut self, id: &uuid::Uuid) {
         let mut unlocked = self.lock().expect("Should unlock");
         match unlocked.iter().position(|x| x.id == *id) {
@@ -70,11 +193,41 @@ pub enum FolderType {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "String", into = "String")]
 pub enum LanguageType {
-    #[serde(rename = "r")]
     R,
-    #[serde(rename = "stata")]
     Stata,
+    Python,
+    // Any other language with a runner set up in the runners file (e.g. "sas", "julia")
+    Other(String),
+}
+
+impl LanguageType {
+    pub fn key(&self) -> &str {
+        match self {
+            LanguageType::R => "r",
+            LanguageType::Stata => "stata",
+            LanguageType::Python => "python",
+            LanguageType::Other(v) => v,
+        }
+    }
+}
+
+impl From<String> for LanguageType {
+    fn from(s: String) -> LanguageType {
+        match s.as_str() {
+            "r" => LanguageType::R,
+            "stata" => LanguageType::Stata,
+            "python" => LanguageType::Python,
+            _ => LanguageType::Other(s),
+        }
+    }
+}
+
+impl From<LanguageType> for String {
+    fn from(l: LanguageType) -> String {
+        l.key().to_string()
+    }
 }
 
 impl<'r> FromParam<'r> for FolderType {
@@ -148,6 +301,26 @@ pub struct AnalysisMetaData {
     pub scheduled: bool,
     #[serde(rename = "lastStatus")]
     pub last_status: StageResult,
+    //
+    #[serde(default)]
+    pub limits: RunLimits,
+    // Only used when the runner is sandboxed, otherwise scripts always have network
+    #[serde(rename = "allowNetwork", default)]
+    pub allow_network: bool,
+    #[serde(default)]
+    pub parameters: Vec<AnalysisParameter>,
+    // Names of secrets to set as env vars when the script runs
+    #[serde(default)]
+    pub secrets: Vec<String>,
+    // None means _DEFAULT_KEEP_OUTPUT_VERSIONS. At least 2 are kept (see promote_output_version)
+    #[serde(rename = "keepOutputVersions", default)]
+    pub keep_output_versions: Option<usize>,
+    // Of the last successful run, see get_run_fingerprint
+    #[serde(rename = "lastSuccessFingerprint", default)]
+    pub last_success_fingerprint: Option<String>,
+    // One of the analysis files (see analysis_files.rs) to run instead of the code
+    #[serde(rename = "entryPoint", default)]
+    pub entry_point: Option<String>,
 }
 
 impl AnalysisMetaData {
@@ -174,10 +347,128 @@ impl AnalysisMetaData {
             last_modified_by: "".to_string(),
             scheduled,
             last_status: StageResult::NA,
+            limits: RunLimits::default(),
+            allow_network: false,
+            parameters: Vec::new(),
+            secrets: Vec::new(),
+            keep_output_versions: None,
+            last_success_fingerprint: None,
+            entry_point: None,
+        }
+    }
+
+    // The file in the workspace that the runner runs
+    pub fn get_script_file_name(&self) -> &str {
+        self.entry_point.as_deref().unwrap_or(_FILE_NAME_MYSCRIPT)
+    }
+
+    // Defaults, with any overrides given when starting the run, in the order the parameters are declared
+    pub fn resolve_parameters(
+        &self,
+        overrides: &HashMap<String, String>,
+    ) -> Result<Vec<ParameterValue>, String> {
+        if let Some(name) = overrides
+            .keys()
+            .find(|x| !self.parameters.iter().any(|p| &p.name == *x))
+        {
+            return Err(format!("Unknown parameter \"{}\"", name));
+        }
+        let mut values = Vec::new();
+        for parameter in self.parameters.iter() {
+            let value = overrides.get(&parameter.name).unwrap_or(&parameter.default);
+            parameter.check(value)?;
+            values.push(ParameterValue {
+                name: parameter.name.clone(),
+                value: value.clone(),
+            });
+        }
+        Ok(values)
+    }
+}
+
+// Passed to the script as arguments (where the runner args have "{params}") and as PARAM_<name> env vars
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnalysisParameter {
+    pub name: String,
+    #[serde(flatten)]
+    pub parameter_type: ParameterType,
+    pub default: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum ParameterType {
+    #[serde(rename = "string")]
+    String,
+    #[serde(rename = "number")]
+    Number,
+    // YYYY-MM-DD
+    #[serde(rename = "date")]
+    Date,
+    #[serde(rename = "choice")]
+    Choice { options: Vec<String> },
+}
+
+impl AnalysisParameter {
+    pub fn check(&self, value: &str) -> Result<(), String> {
+        let valid_name = self
+            .name
+            .chars()
+            .next()
+            .is_some_and(|x| x.is_ascii_alphabetic())
+            && self
+                .name
+                .chars()
+                .all(|x| x.is_ascii_alphanumeric() || x == '_');
+        if !valid_name {
+            return Err(format!(
+                "Parameter name \"{}\" should be letters, numbers and underscores",
+                self.name
+            ));
+        }
+        let valid_value = match &self.parameter_type {
+            ParameterType::String => true,
+            ParameterType::Number => value.parse::<f64>().is_ok_and(|x| x.is_finite()),
+            ParameterType::Date => chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok(),
+            ParameterType::Choice { options } => options.iter().any(|x| x == value),
+        };
+        if !valid_value {
+            return Err(format!(
+                "\"{}\" is not a valid value for parameter \"{}\"",
+                value, self.name
+            ));
         }
+        Ok(())
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ParameterValue {
+    pub name: String,
+    pub value: String,
+}
+
+// What a run was started with, besides the analysis itself
+pub struct RunSettings {
+    pub parameters: Vec<ParameterValue>,
+    pub secret_values: Vec<SecretValue>,
+    // Run even if nothing changed since the last successful run
+    pub force: bool,
+    // Of the shared library, None if there is none yet
+    pub library_version: Option<u64>,
+}
+
+// Limits are checked while the script runs (CleanRun stage only). None means no limit.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RunLimits {
+    #[serde(rename = "timeoutSeconds")]
+    pub timeout_seconds: Option<u64>,
+    #[serde(rename = "memoryMb")]
+    pub memory_mb: Option<u64>,
+    #[serde(rename = "cpuSeconds")]
+    pub cpu_seconds: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InputFile {
     #[serde(rename = "folderType")]
@@ -188,6 +479,9 @@ pub struct InputFile {
     pub file_name: String,
 }
 
+// The file name can be an exact path ("table.csv", "figs/map.png"), a glob ("figs/*.png",
+// "**/*.csv") or a folder ending in "/" ("figs/") for everything in it. Paths are relative
+// to the analysis folder, and files starting with "." are never matched by globs or folders.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OutputFile {
     #[serde(rename = "fileName")]
@@ -195,6 +489,53 @@ pub struct OutputFile {
     pub public: bool,
 }
 
+impl OutputFile {
+    pub fn is_exact(&self) -> bool {
+        !self.is_folder() && !self.file_name.contains(['*', '?', '['])
+    }
+
+    pub fn is_folder(&self) -> bool {
+        self.file_name.ends_with('/')
+    }
+
+    // Has to stay inside the analysis folder
+    pub fn is_valid(&self) -> bool {
+        let path = Path::new(&self.file_name);
+        !self.file_name.is_empty()
+            && path
+                .components()
+                .all(|x| matches!(x, std::path::Component::Normal(_)))
+            && (self.is_exact() || glob::Pattern::new(&self.file_name).is_ok())
+    }
+
+    pub fn matches(&self, relative_path: &str) -> bool {
+        if self.is_exact() {
+            return self.file_name == relative_path;
+        }
+        if relative_path.split('/').any(|x| x.starts_with('.')) {
+            return false;
+        }
+        if self.is_folder() {
+            return relative_path.starts_with(&self.file_name);
+        }
+        let match_options = glob::MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: true,
+        };
+        glob::Pattern::new(&self.file_name)
+            .map(|x| x.matches_with(relative_path, match_options))
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredOutputFile {
+    #[serde(rename = "fileName")]
+    pub file_name: String,
+    pub public: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DataFile {
     #[serde(rename = "fileName")]
@@ -209,27 +550,77 @@ pub struct CheckFileResponse {
     pub date: String,
     pub size: u64,
     pub public: bool,
+    // The output version the file came from, None for data files and outputs from before versions
+    pub version: Option<u64>,
+}
+
+// Each successful run's outputs are kept in .versions/<version>, with this in _FILE_NAME_VERSION_INFO
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutputVersion {
+    pub version: u64,
+    #[serde(rename = "runId")]
+    pub run_id: uuid::Uuid,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "fileNames")]
+    pub file_names: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutputFileVersion {
+    pub version: u64,
+    #[serde(rename = "runId")]
+    pub run_id: uuid::Uuid,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    pub size: u64,
+    pub current: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RealTimeMessage {
     #[serde(rename = "msgType")]
     pub msg_type: MessageType,
+    #[serde(rename = "runId")]
+    pub run_id: Option<uuid::Uuid>,
     pub stage: Option<Stage>,
     #[serde(rename = "stageResult")]
     pub stage_result: Option<StageResult>,
     pub log: Option<String>,
+    #[serde(rename = "exitCode")]
+    pub exit_code: Option<i32>,
+    pub diagnostic: Option<Box<ScriptDiagnostic>>,
+}
+
+// An error (or warning) worked out from what the interpreter printed, see diagnostics.rs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScriptDiagnostic {
+    pub message: String,
+    // e.g. 601 for Stata's "r(601);"
+    #[serde(rename = "returnCode")]
+    pub return_code: Option<i32>,
+    pub command: Option<String>,
+    // In the script, where it can be worked out
+    pub line: Option<u32>,
+    // Innermost call first
+    #[serde(default)]
+    pub traceback: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum MessageType {
+    RunStarted,
     Heartbeat,
     Waiting,
     Stage,
     LogOut,
     LogErr,
+    Exit,
     EndSuccess,
     EndFailure,
+    EndCancelled,
+    ScriptError,
+    ScriptWarning,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -246,6 +637,48 @@ pub enum StageResult {
     Pending,
     Success,
     Failure,
+    LimitExceeded,
+    Cancelled,
+    // Skipped because nothing changed since the last successful run
+    UpToDate,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunRecord {
+    #[serde(rename = "runId")]
+    pub run_id: uuid::Uuid,
+    #[serde(rename = "analysisId")]
+    pub analysis_id: String,
+    #[serde(rename = "startedAt")]
+    pub started_at: DateTime<Utc>,
+    #[serde(rename = "endedAt")]
+    pub ended_at: Option<DateTime<Utc>>,
+    #[serde(rename = "startedBy")]
+    pub started_by: String,
+    pub status: StageResult,
+    pub stages: Vec<StageRecord>,
+    #[serde(rename = "exitCode")]
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub errors: Vec<ScriptDiagnostic>,
+    #[serde(default)]
+    pub warnings: Vec<ScriptDiagnostic>,
+    #[serde(default)]
+    pub parameters: Vec<ParameterValue>,
+    #[serde(rename = "libraryVersion", default)]
+    pub library_version: Option<u64>,
+    // LogOut and LogErr messages, in the order they were sent
+    pub log: Vec<RealTimeMessage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StageRecord {
+    pub stage: Stage,
+    pub result: StageResult,
+    #[serde(rename = "startedAt")]
+    pub started_at: DateTime<Utc>,
+    #[serde(rename = "endedAt")]
+    pub ended_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
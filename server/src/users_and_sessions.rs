@@ -39,7 +39,7 @@ pub struct UserWithRoles {
     pub name: String,
     pub email: String,
     #[serde(rename = "isAdmin")]
-    is_admin: bool,
+    pub is_admin: bool,
     #[serde(rename = "canEdit")]
     pub can_edit: bool,
 }
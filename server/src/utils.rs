@@ -9,6 +9,7 @@ pub fn run_startup_checker() {
     check_path(_DATA_FOLDER, "Data folder");
     check_path(_HTML_FOLDER, "HTML folder");
     check_path(_TEMP_FOLDER, "Temp folder");
+    check_runners();
     println!("\nAll good!\n");
 }
 
@@ -21,6 +22,67 @@ fn check_path(p: &str, label: &str) {
     }
 }
 
+fn check_runners() {
+    let runners_file_exists = Path::new(_RUNNERS_FILE_PATH).exists();
+    if !runners_file_exists {
+        println!("Runners file does not exist, using built-in runners");
+    }
+    match RunnerRegistry::load() {
+        Some(registry) => {
+            if runners_file_exists {
+                let languages: Vec<&str> =
+                    registry.runners.iter().map(|x| x.language.key()).collect();
+                println!("Runners file exists ({})", languages.join(", "));
+            }
+            for runner in registry.runners.iter() {
+                check_interpreter(runner);
+            }
+            for runner in registry.runners.iter() {
+                if let Some(max_parallel_runs) = runner.max_parallel_runs {
+                    println!(
+                        "Up to {} {} runs at once",
+                        max_parallel_runs.max(1),
+                        runner.language.key()
+                    );
+                }
+            }
+            for sandbox in registry.runners.iter().filter_map(|x| x.sandbox.as_ref()) {
+                check_path(&sandbox.bwrap_path, "Sandbox (bwrap)");
+            }
+        }
+        None => {
+            println!("ERROR! Runners file could not be read\n");
+            panic!("ERROR! Runners file could not be read\n\n");
+        }
+    }
+}
+
+// Not having an interpreter is allowed (e.g. no Stata on a dev machine), its runs just fail
+fn check_interpreter(runner: &RunnerConfig) {
+    let language = runner.language.key();
+    match runner.find_executable() {
+        Some(path) => match (&runner.version_args, runner.get_version()) {
+            (_, Some(version)) => {
+                println!("{} interpreter: {} ({})", language, path.display(), version)
+            }
+            (Some(_), None) => println!(
+                "{} interpreter: {} (could not read its version)",
+                language,
+                path.display()
+            ),
+            (None, None) => println!(
+                "{} interpreter: {} (version not checked, no versionArgs)",
+                language,
+                path.display()
+            ),
+        },
+        None => println!(
+            "WARNING! {} interpreter \"{}\" not found, {} runs will fail",
+            language, runner.executable, language
+        ),
+    }
+}
+
 pub fn get_list_of_analyses() -> Option<Vec<AnalysisSummary>> {
     let mut analyses: Vec<AnalysisSummary> = Vec::new();
     let analyses_path = PathBuf::from(_ANALYSES_FOLDER);
@@ -40,8 +102,29 @@ pub fn get_list_of_analyses() -> Option<Vec<AnalysisSummary>> {
 }
 
 pub fn get_analysis_ids_for_scheduler_in_order(
-    mut analyses: Vec<AnalysisSummary>,
+    analyses: Vec<AnalysisSummary>,
 ) -> Option<Vec<String>> {
+    let analyses_in_order = get_analyses_in_order(analyses);
+    // Only include those that are scheduled (but do this after ordering)
+    let analysis_ids = analyses_in_order
+        .iter()
+        .filter(|x| x.metadata.scheduled)
+        .map(|x| x.id.clone())
+        .collect();
+    Some(analysis_ids)
+}
+
+// The analyses whose outputs it uses (may have duplicates)
+pub fn get_upstream_analysis_ids(a: &AnalysisSummary) -> impl Iterator<Item = &String> {
+    a.metadata
+        .inputs
+        .iter()
+        .filter(|x| x.folder_type == FolderType::Analysis)
+        .map(|x| &x.analysis_id)
+}
+
+// Upstream analyses (inputs from other analyses) before the ones that use them
+pub fn get_analyses_in_order(mut analyses: Vec<AnalysisSummary>) -> Vec<AnalysisSummary> {
     // Put in alpha order for cleanliness (must do this before selecting based on dependencies)
     analyses.sort_by(|a, b| {
         a.metadata
@@ -67,13 +150,7 @@ pub fn get_analysis_ids_for_scheduler_in_order(
         }
         analyses.retain(|x| !analyses_for_scheduler.iter().any(|e| e.id == x.id));
     }
-    // Only include those that are scheduled (but do this after ordering)
-    analyses_for_scheduler.retain(|x| x.metadata.scheduled);
-    let analysis_ids = analyses_for_scheduler
-        .iter()
-        .map(|x| x.id.clone())
-        .collect();
-    Some(analysis_ids)
+    analyses_for_scheduler
 }
 
 pub fn get_list_of_data_files() -> Option<Vec<DataFile>> {
@@ -124,11 +201,17 @@ pub fn get_check_file_response(
         get_file_public_status(analysis_id, file_name).unwrap_or(false)
     };
     let datetime: DateTime<Utc> = system_time.into();
+    let version = if folder_type == FolderType::Data {
+        None
+    } else {
+        get_current_output_version(analysis_id, file_name)
+    };
     Some(CheckFileResponse {
         exists: true,
         date: datetime.to_rfc3339().to_string(),
         size: m.len(),
         public,
+        version,
     })
 }
 
@@ -145,16 +228,266 @@ pub fn save_new_users(new_users: Vec<UserFull>) -> Option<()> {
     Some(())
 }
 
-pub fn get_file_public_status(analysis_id: &String, file_name: &String) -> Option<bool> {
+pub fn get_stored_secrets() -> Option<Vec<StoredSecret>> {
+    let secrets_file_path = PathBuf::from(_SECRETS_FILE_PATH);
+    if !secrets_file_path.exists() {
+        return Some(Vec::new());
+    }
+    let secrets_str = read_to_string(secrets_file_path).ok()?;
+    serde_json::from_str(&secrets_str).ok()?
+}
+
+pub fn save_stored_secrets(new_secrets: Vec<StoredSecret>) -> Option<()> {
+    let secrets_file_path = PathBuf::from(_SECRETS_FILE_PATH);
+    let new_secrets_str = serde_json::to_string_pretty(&new_secrets).ok()?;
+    write(secrets_file_path, new_secrets_str).ok()?;
+    Some(())
+}
+
+pub fn get_stored_schedules() -> Option<Vec<Schedule>> {
+    let schedules_file_path = PathBuf::from(_SCHEDULES_FILE_PATH);
+    if !schedules_file_path.exists() {
+        return Some(Vec::new());
+    }
+    let schedules_str = read_to_string(schedules_file_path).ok()?;
+    serde_json::from_str(&schedules_str).ok()
+}
+
+pub fn save_stored_schedules(schedules: &[Schedule]) -> Option<()> {
+    let schedules_file_path = PathBuf::from(_SCHEDULES_FILE_PATH);
+    let schedules_str = serde_json::to_string_pretty(schedules).ok()?;
+    write(schedules_file_path, schedules_str).ok()?;
+    Some(())
+}
+
+pub fn get_file_public_status(analysis_id: &String, file_name: &str) -> Option<bool> {
     // Returns None if file data not found
     // Returns Some(false) if not public
     // Returns Some(true) if public
     let metadata = get_metadata_from_analysis_id(&analysis_id)?;
-    let to_check = file_name.clone();
-    let file = metadata.outputs.iter().find(|x| x.file_name == to_check)?;
+    let file = metadata.outputs.iter().find(|x| x.matches(file_name))?;
     Some(file.public)
 }
 
+// Every file the analysis has stored for its outputs, e.g. each file a glob output matched
+pub fn get_stored_output_files(analysis_id: &String) -> Option<Vec<StoredOutputFile>> {
+    let metadata = get_metadata_from_analysis_id(analysis_id)?;
+    let folder_path = match get_current_output_version_number(analysis_id) {
+        Some(version) => get_path_to_output_versions(analysis_id).join(version.to_string()),
+        None => PathBuf::from(_ANALYSES_FOLDER).join(analysis_id),
+    };
+    let mut stored_output_files = Vec::new();
+    for file_name in get_relative_file_paths(&folder_path) {
+        if let Some(output) = metadata.outputs.iter().find(|x| x.matches(&file_name)) {
+            stored_output_files.push(StoredOutputFile {
+                file_name,
+                public: output.public,
+            });
+        }
+    }
+    Some(stored_output_files)
+}
+
+// Newest first
+pub fn get_output_versions(analysis_id: &String) -> Vec<OutputVersion> {
+    let versions_path = get_path_to_output_versions(analysis_id);
+    let mut output_versions: Vec<OutputVersion> = match read_dir(versions_path) {
+        Ok(entries) => entries
+            .flatten()
+            // Skips staging folders
+            .filter(|x| x.file_name().to_string_lossy().parse::<u64>().is_ok())
+            .filter_map(|x| {
+                let info_str = read_to_string(x.path().join(_FILE_NAME_VERSION_INFO)).ok()?;
+                serde_json::from_str(&info_str).ok()
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    output_versions.sort_by_key(|x| std::cmp::Reverse(x.version));
+    output_versions
+}
+
+// None if the analysis has no versions yet (outputs from before versions are in the analysis folder)
+pub fn get_current_output_version_number(analysis_id: &String) -> Option<u64> {
+    let current_path = get_path_to_output_versions(analysis_id).join(_FILE_NAME_CURRENT_VERSION);
+    read_to_string(current_path).ok()?.trim().parse().ok()
+}
+
+// Only if the file is in the current version
+pub fn get_current_output_version(analysis_id: &String, file_name: &String) -> Option<u64> {
+    let version = get_current_output_version_number(analysis_id)?;
+    get_output_versions(analysis_id)
+        .into_iter()
+        .find(|x| x.version == version && x.file_names.contains(file_name))
+        .map(|x| x.version)
+}
+
+pub fn get_output_file_versions(
+    analysis_id: &String,
+    file_name: &String,
+) -> Vec<OutputFileVersion> {
+    let current_version = get_current_output_version_number(analysis_id);
+    get_output_versions(analysis_id)
+        .into_iter()
+        .filter(|x| x.file_names.contains(file_name))
+        .map(|x| OutputFileVersion {
+            version: x.version,
+            run_id: x.run_id,
+            created_at: x.created_at,
+            size: metadata(get_path_to_output_version_file(
+                analysis_id,
+                x.version,
+                file_name,
+            ))
+            .map(|m| m.len())
+            .unwrap_or(0),
+            current: Some(x.version) == current_version,
+        })
+        .collect()
+}
+
+fn get_path_to_output_versions(analysis_id: &String) -> PathBuf {
+    PathBuf::from(_ANALYSES_FOLDER)
+        .join(analysis_id)
+        .join(_FOLDER_NAME_VERSIONS)
+}
+
+pub fn get_path_to_analysis_files(analysis_id: &String) -> PathBuf {
+    PathBuf::from(_ANALYSES_FOLDER)
+        .join(analysis_id)
+        .join(_FOLDER_NAME_FILES)
+}
+
+pub fn get_path_to_output_version_file(
+    analysis_id: &String,
+    version: u64,
+    file_name: &String,
+) -> PathBuf {
+    get_path_to_output_versions(analysis_id)
+        .join(version.to_string())
+        .join(file_name)
+}
+
+// The files are copied into a staging folder, which is renamed into the next version once
+// complete. Then _FILE_NAME_CURRENT_VERSION is replaced (also a rename) to point at it, so
+// readers see either all the old outputs or all the new ones. Old versions beyond
+// keep_versions are removed after that. If anything fails, the staging folder is removed and
+// the current outputs are left as they were.
+pub fn promote_output_version(
+    analysis_id: &String,
+    run_id: &uuid::Uuid,
+    from_path: &Path,
+    file_names: &[String],
+    keep_versions: usize,
+) -> Option<u64> {
+    let versions_path = get_path_to_output_versions(analysis_id);
+    let staging_path = versions_path.join(format!(".staging-{}", run_id));
+    let version = stage_output_version(&staging_path, run_id, from_path, file_names)
+        .and_then(|_| rename_staged_output_version(analysis_id, &staging_path));
+    let version = match version {
+        Some(v) => v,
+        None => {
+            let _ = remove_dir_all(&staging_path);
+            return None;
+        }
+    };
+    let temp_current_path = versions_path.join(format!(".current-{}", run_id));
+    write(&temp_current_path, version.to_string()).ok()?;
+    rename(
+        &temp_current_path,
+        versions_path.join(_FILE_NAME_CURRENT_VERSION),
+    )
+    .ok()?;
+    // The version that was current until now is always kept, as a downstream run may still be
+    // importing from it. It goes with the next promotion instead
+    for old_version in get_output_versions(analysis_id)
+        .iter()
+        .skip(keep_versions.max(2))
+    {
+        let _ = remove_dir_all(versions_path.join(old_version.version.to_string()));
+    }
+    Some(version)
+}
+
+fn stage_output_version(
+    staging_path: &Path,
+    run_id: &uuid::Uuid,
+    from_path: &Path,
+    file_names: &[String],
+) -> Option<()> {
+    for file_name in file_names.iter() {
+        let to_path = staging_path.join(file_name);
+        create_dir_all(to_path.parent()?).ok()?;
+        copy(from_path.join(file_name), to_path).ok()?;
+    }
+    let output_version = OutputVersion {
+        // Set when renamed
+        version: 0,
+        run_id: *run_id,
+        created_at: chrono::Utc::now(),
+        file_names: file_names.to_vec(),
+    };
+    create_dir_all(staging_path).ok()?;
+    let json_string = serde_json::to_string_pretty(&output_version).ok()?;
+    write(staging_path.join(_FILE_NAME_VERSION_INFO), json_string).ok()?;
+    Some(())
+}
+
+// Another run of the same analysis can take a version number first, then this tries the next one
+fn rename_staged_output_version(analysis_id: &String, staging_path: &Path) -> Option<u64> {
+    let info_path = staging_path.join(_FILE_NAME_VERSION_INFO);
+    let mut output_version: OutputVersion =
+        serde_json::from_str(&read_to_string(&info_path).ok()?).ok()?;
+    for _ in 0..10 {
+        let version = get_output_versions(analysis_id)
+            .first()
+            .map_or(1, |x| x.version + 1);
+        output_version.version = version;
+        let json_string = serde_json::to_string_pretty(&output_version).ok()?;
+        write(&info_path, json_string).ok()?;
+        let version_path = get_path_to_output_versions(analysis_id).join(version.to_string());
+        if rename(staging_path, version_path).is_ok() {
+            return Some(version);
+        }
+    }
+    None
+}
+
+// Relative paths (with "/") of all files under the folder, skipping anything starting with "."
+pub fn get_relative_file_paths(folder_path: &Path) -> Vec<String> {
+    let mut relative_file_paths = Vec::new();
+    add_relative_file_paths(folder_path, "", &mut relative_file_paths);
+    relative_file_paths.sort();
+    relative_file_paths
+}
+
+fn add_relative_file_paths(
+    folder_path: &Path,
+    prefix: &str,
+    relative_file_paths: &mut Vec<String>,
+) {
+    let entries = match read_dir(folder_path) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        let relative_path = format!("{}{}", prefix, name);
+        if entry.path().is_dir() {
+            add_relative_file_paths(
+                &entry.path(),
+                &format!("{}/", relative_path),
+                relative_file_paths,
+            );
+        } else {
+            relative_file_paths.push(relative_path);
+        }
+    }
+}
+
 pub fn get_analysis_package(analysis_id: &String) -> Option<AnalysisPackage> {
     let folder_path = PathBuf::from(_ANALYSES_FOLDER).join(analysis_id);
     let code_file_path = folder_path.join(_FILE_NAME_MYSCRIPT);
@@ -172,9 +505,12 @@ pub fn get_path_to_file(
     file_name: &String,
 ) -> PathBuf {
     match folder_type {
-        FolderType::Analysis => PathBuf::from(_ANALYSES_FOLDER)
-            .join(analysis_id)
-            .join(file_name),
+        FolderType::Analysis => match get_current_output_version_number(analysis_id) {
+            Some(version) => get_path_to_output_version_file(analysis_id, version, file_name),
+            None => PathBuf::from(_ANALYSES_FOLDER)
+                .join(analysis_id)
+                .join(file_name),
+        },
         FolderType::Data => PathBuf::from(_DATA_FOLDER).join(file_name),
         // FolderType::Temp => PathBuf::from(_TEMP_FOLDER)
         //     .join(analysis_id)
@@ -217,6 +553,61 @@ pub fn update_metadata_after_run(
     Some(())
 }
 
+pub fn save_metadata(analysis_id: &String, metadata: &AnalysisMetaData) -> Option<()> {
+    let metadata_file_path = PathBuf::from(_ANALYSES_FOLDER)
+        .join(analysis_id)
+        .join(_FILE_NAME_MYMETADATA);
+    let json_string = serde_json::to_string_pretty(metadata).ok()?;
+    write(metadata_file_path, json_string).ok()?;
+    Some(())
+}
+
+pub fn update_metadata_fingerprint(analysis_id: &String, fingerprint: &str) -> Option<()> {
+    let metadata_file_path = PathBuf::from(_ANALYSES_FOLDER)
+        .join(analysis_id)
+        .join(_FILE_NAME_MYMETADATA);
+    let mut metadata = get_metadata_from_path(&metadata_file_path)?;
+    metadata.last_success_fingerprint = Some(fingerprint.to_string());
+    let json_string = serde_json::to_string_pretty(&metadata).ok()?;
+    write(metadata_file_path, json_string).ok()?;
+    Some(())
+}
+
+// SHA-256 of everything that decides what a run produces: the script, language, outputs,
+// parameter values, library version, the other source files and each input file with their content
+pub fn get_run_fingerprint(
+    a: &AnalysisPackage,
+    parameters: &[ParameterValue],
+    library_version: Option<u64>,
+) -> Option<String> {
+    let mut hasher = Sha256::new();
+    let settings = (
+        &a.code,
+        &a.metadata.language,
+        &a.metadata.inputs,
+        &a.metadata.outputs,
+        parameters,
+        &a.metadata.entry_point,
+        library_version,
+    );
+    hasher.update(serde_json::to_string(&settings).ok()?);
+    let files_path = get_path_to_analysis_files(&a.id);
+    for file_name in get_analysis_file_names(&a.id) {
+        let mut file = File::open(files_path.join(&file_name)).ok()?;
+        hasher.update(file_name.len().to_le_bytes());
+        hasher.update(&file_name);
+        hasher.update(file.metadata().ok()?.len().to_le_bytes());
+        std::io::copy(&mut file, &mut hasher).ok()?;
+    }
+    for input in a.metadata.inputs.iter() {
+        let path = get_path_to_file(&input.folder_type, &input.analysis_id, &input.file_name);
+        let mut file = File::open(path).ok()?;
+        hasher.update(file.metadata().ok()?.len().to_le_bytes());
+        std::io::copy(&mut file, &mut hasher).ok()?;
+    }
+    Some(format!("{:x}", hasher.finalize()))
+}
+
 pub fn get_metadata_from_analysis_id(analysis_id: &String) -> Option<AnalysisMetaData> {
     let metadata_file_path = PathBuf::from(_ANALYSES_FOLDER)
         .join(analysis_id)
@@ -228,3 +619,57 @@ pub fn get_metadata_from_path(metadata_file_path: &PathBuf) -> Option<AnalysisMe
     let metadata_str = read_to_string(metadata_file_path).ok()?;
     serde_json::from_str(&metadata_str).ok()
 }
+
+pub fn save_run_record(record: &RunRecord) -> Option<()> {
+    let folder_path = PathBuf::from(_ANALYSES_FOLDER).join(&record.analysis_id);
+    // Don't bring back the folder of an analysis that was deleted during the run
+    if !folder_path.exists() {
+        return None;
+    }
+    let runs_folder_path = folder_path.join(_FOLDER_NAME_RUNS);
+    DirBuilder::new()
+        .recursive(true)
+        .create(&runs_folder_path)
+        .ok()?;
+    let json_string = serde_json::to_string_pretty(record).ok()?;
+    write(
+        runs_folder_path.join(format!("{}.json", record.run_id)),
+        json_string,
+    )
+    .ok()?;
+    Some(())
+}
+
+pub fn get_run_records(analysis_id: &String) -> Option<Vec<RunRecord>> {
+    let mut records: Vec<RunRecord> = Vec::new();
+    let runs_folder_path = PathBuf::from(_ANALYSES_FOLDER)
+        .join(analysis_id)
+        .join(_FOLDER_NAME_RUNS);
+    if !runs_folder_path.exists() {
+        return Some(records);
+    }
+    for entry in read_dir(runs_folder_path).ok()? {
+        let record_str = read_to_string(entry.ok()?.path()).ok()?;
+        let mut record: RunRecord = serde_json::from_str(&record_str).ok()?;
+        // Logs can be big, use get_run_record for those
+        record.log = Vec::new();
+        records.push(record);
+    }
+    records.sort_by_key(|x| std::cmp::Reverse(x.started_at));
+    Some(records)
+}
+
+pub fn get_run_record(run_id: &uuid::Uuid) -> Option<RunRecord> {
+    for entry in read_dir(_ANALYSES_FOLDER).ok()? {
+        let record_file_path = entry
+            .ok()?
+            .path()
+            .join(_FOLDER_NAME_RUNS)
+            .join(format!("{}.json", run_id));
+        if record_file_path.exists() {
+            let record_str = read_to_string(record_file_path).ok()?;
+            return serde_json::from_str(&record_str).ok();
+        }
+    }
+    None
+}
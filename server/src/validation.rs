@@ -0,0 +1,330 @@
+use super::*;
+
+// Problems with the inputs of analyses. get_analyses_in_order leaves out analyses in a cycle or
+// using a missing analysis (and everything downstream of them), so the scheduler never runs them,
+// and a missing data file or an output the upstream analysis doesn't declare fails the run when
+// importing inputs.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum DependencyProblem {
+    // Each uses an output of the next one, and the last one an output of the first
+    #[serde(rename = "cycle")]
+    Cycle {
+        #[serde(rename = "analysisIds")]
+        analysis_ids: Vec<String>,
+    },
+    #[serde(rename = "missingAnalysis")]
+    MissingAnalysis {
+        #[serde(rename = "analysisId")]
+        analysis_id: String,
+        #[serde(rename = "upstreamAnalysisId")]
+        upstream_analysis_id: String,
+        #[serde(rename = "fileName")]
+        file_name: String,
+    },
+    #[serde(rename = "missingDataFile")]
+    MissingDataFile {
+        #[serde(rename = "analysisId")]
+        analysis_id: String,
+        #[serde(rename = "fileName")]
+        file_name: String,
+    },
+    #[serde(rename = "undeclaredOutput")]
+    UndeclaredOutput {
+        #[serde(rename = "analysisId")]
+        analysis_id: String,
+        #[serde(rename = "upstreamAnalysisId")]
+        upstream_analysis_id: String,
+        #[serde(rename = "fileName")]
+        file_name: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DependencyProblemReport {
+    #[serde(flatten)]
+    pub problem: DependencyProblem,
+    pub message: String,
+}
+
+// What /updateanalysis responds with when it refuses to save
+pub type RefusedSave = rocket::response::status::Custom<Json<Vec<DependencyProblemReport>>>;
+
+// What /updateanalysis responds with when it saves, with the problems involving the analysis that
+// didn't stop it being saved
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedAnalysis {
+    #[serde(rename = "analysisPackage")]
+    pub analysis_package: AnalysisPackage,
+    pub problems: Vec<DependencyProblemReport>,
+}
+
+impl DependencyProblem {
+    // The analysis has the problem, or causes it by not declaring the output
+    pub fn involves(&self, analysis_id: &String) -> bool {
+        match self {
+            DependencyProblem::Cycle { analysis_ids } => analysis_ids.contains(analysis_id),
+            DependencyProblem::MissingDataFile {
+                analysis_id: id, ..
+            } => id == analysis_id,
+            DependencyProblem::MissingAnalysis {
+                analysis_id: id,
+                upstream_analysis_id,
+                ..
+            }
+            | DependencyProblem::UndeclaredOutput {
+                analysis_id: id,
+                upstream_analysis_id,
+                ..
+            } => id == analysis_id || upstream_analysis_id == analysis_id,
+        }
+    }
+
+    // Saving an analysis with a new cycle or an input from a missing analysis is refused, since
+    // the scheduler would never run it. The other problems are only reported: a data file can be
+    // uploaded and an upstream output declared after the analysis using it is saved
+    pub fn blocks_save(&self) -> bool {
+        matches!(
+            self,
+            DependencyProblem::Cycle { .. } | DependencyProblem::MissingAnalysis { .. }
+        )
+    }
+
+    // A cycle can come out starting from a different analysis or going a different way round when
+    // something else changes, so cycles are the same if they go through the same analyses
+    fn is_same_as(&self, other: &DependencyProblem) -> bool {
+        match (self, other) {
+            (
+                DependencyProblem::Cycle { analysis_ids },
+                DependencyProblem::Cycle {
+                    analysis_ids: other_ids,
+                },
+            ) => {
+                let ids: HashSet<&String> = analysis_ids.iter().collect();
+                ids == other_ids.iter().collect()
+            }
+            _ => self == other,
+        }
+    }
+
+    fn describe(&self, analyses: &[AnalysisSummary]) -> String {
+        let name = |id: &String| {
+            analyses
+                .iter()
+                .find(|x| &x.id == id)
+                .map_or(id.clone(), |x| format!("\"{}\"", x.metadata.name))
+        };
+        match self {
+            DependencyProblem::Cycle { analysis_ids } => {
+                let uses: Vec<String> = analysis_ids
+                    .iter()
+                    .zip(analysis_ids.iter().cycle().skip(1))
+                    .map(|(a, b)| format!("{} uses an output of {}", name(a), name(b)))
+                    .collect();
+                format!("Dependency cycle: {}", uses.join(", "))
+            }
+            DependencyProblem::MissingAnalysis {
+                analysis_id,
+                upstream_analysis_id,
+                file_name,
+            } => format!(
+                "{} uses {} from analysis {}, which doesn't exist",
+                name(analysis_id),
+                file_name,
+                upstream_analysis_id
+            ),
+            DependencyProblem::MissingDataFile {
+                analysis_id,
+                file_name,
+            } => format!(
+                "{} uses data file {}, which doesn't exist",
+                name(analysis_id),
+                file_name
+            ),
+            DependencyProblem::UndeclaredOutput {
+                analysis_id,
+                upstream_analysis_id,
+                file_name,
+            } => format!(
+                "{} uses {} from {}, which isn't one of its outputs",
+                name(analysis_id),
+                file_name,
+                name(upstream_analysis_id)
+            ),
+        }
+    }
+}
+
+pub fn get_dependency_problems(analyses: &[AnalysisSummary]) -> Vec<DependencyProblem> {
+    let mut problems: Vec<DependencyProblem> = get_dependency_cycles(analyses)
+        .into_iter()
+        .map(|x| DependencyProblem::Cycle { analysis_ids: x })
+        .collect();
+    for a in analyses.iter() {
+        for input in a.metadata.inputs.iter() {
+            let problem = match input.folder_type {
+                FolderType::Data => {
+                    if PathBuf::from(_DATA_FOLDER).join(&input.file_name).is_file() {
+                        continue;
+                    }
+                    DependencyProblem::MissingDataFile {
+                        analysis_id: a.id.clone(),
+                        file_name: input.file_name.clone(),
+                    }
+                }
+                FolderType::Analysis => match analyses.iter().find(|x| x.id == input.analysis_id) {
+                    None => DependencyProblem::MissingAnalysis {
+                        analysis_id: a.id.clone(),
+                        upstream_analysis_id: input.analysis_id.clone(),
+                        file_name: input.file_name.clone(),
+                    },
+                    Some(upstream) => {
+                        let outputs = &upstream.metadata.outputs;
+                        if outputs.iter().any(|x| x.matches(&input.file_name)) {
+                            continue;
+                        }
+                        DependencyProblem::UndeclaredOutput {
+                            analysis_id: a.id.clone(),
+                            upstream_analysis_id: upstream.id.clone(),
+                            file_name: input.file_name.clone(),
+                        }
+                    }
+                },
+            };
+            problems.push(problem);
+        }
+    }
+    problems
+}
+
+// At least one cycle for each group of analyses that depend on each other
+fn get_dependency_cycles(analyses: &[AnalysisSummary]) -> Vec<Vec<String>> {
+    let mut analysis_ids: Vec<&String> = analyses.iter().map(|x| &x.id).collect();
+    analysis_ids.sort();
+    let mut finished: HashSet<&String> = HashSet::new();
+    let mut cycles: Vec<Vec<String>> = Vec::new();
+    for analysis_id in analysis_ids {
+        find_cycles(
+            analysis_id,
+            analyses,
+            &mut Vec::new(),
+            &mut finished,
+            &mut cycles,
+        );
+    }
+    cycles
+}
+
+// Depth first through the upstream analyses, "path" being the ones on the way here
+fn find_cycles<'a>(
+    analysis_id: &'a String,
+    analyses: &'a [AnalysisSummary],
+    path: &mut Vec<&'a String>,
+    finished: &mut HashSet<&'a String>,
+    cycles: &mut Vec<Vec<String>>,
+) {
+    if finished.contains(analysis_id) {
+        return;
+    }
+    if let Some(i) = path.iter().position(|x| *x == analysis_id) {
+        let mut cycle: Vec<String> = path[i..].iter().map(|x| x.to_string()).collect();
+        // Starting from the smallest id, so it's reported the same way every time
+        if let Some(start) = (0..cycle.len()).min_by_key(|x| &cycle[*x]) {
+            cycle.rotate_left(start);
+        }
+        cycles.push(cycle);
+        return;
+    }
+    let a = match analyses.iter().find(|x| &x.id == analysis_id) {
+        Some(v) => v,
+        None => return,
+    };
+    let mut upstream_ids: Vec<&String> = get_upstream_analysis_ids(a).collect();
+    upstream_ids.sort();
+    upstream_ids.dedup();
+    path.push(analysis_id);
+    for upstream_id in upstream_ids {
+        find_cycles(upstream_id, analyses, path, finished, cycles);
+    }
+    path.pop();
+    finished.insert(analysis_id);
+}
+
+// All analyses, with this one's metadata replaced
+fn get_analyses_with_metadata(
+    analysis_id: &String,
+    metadata: &AnalysisMetaData,
+) -> Option<Vec<AnalysisSummary>> {
+    let mut analyses = get_list_of_analyses()?;
+    for a in analyses.iter_mut().filter(|x| &x.id == analysis_id) {
+        a.metadata = metadata.clone();
+    }
+    Some(analyses)
+}
+
+// The problems saving this metadata would cause, that aren't there already and block saving it
+pub fn get_new_blocking_problems(
+    analysis_id: &String,
+    metadata: &AnalysisMetaData,
+) -> Option<Vec<DependencyProblemReport>> {
+    let problems = get_dependency_problems(&get_list_of_analyses()?);
+    let analyses = get_analyses_with_metadata(analysis_id, metadata)?;
+    let new_problems = get_dependency_problems(&analyses)
+        .into_iter()
+        .filter(|x| x.blocks_save() && !problems.iter().any(|y| x.is_same_as(y)))
+        .collect();
+    Some(get_dependency_problem_reports(new_problems, &analyses))
+}
+
+// The problems involving the analysis if it were saved with this metadata
+pub fn get_problems_involving(
+    analysis_id: &String,
+    metadata: &AnalysisMetaData,
+) -> Option<Vec<DependencyProblemReport>> {
+    let analyses = get_analyses_with_metadata(analysis_id, metadata)?;
+    let problems = get_dependency_problems(&analyses)
+        .into_iter()
+        .filter(|x| x.involves(analysis_id))
+        .collect();
+    Some(get_dependency_problem_reports(problems, &analyses))
+}
+
+fn get_dependency_problem_reports(
+    problems: Vec<DependencyProblem>,
+    analyses: &[AnalysisSummary],
+) -> Vec<DependencyProblemReport> {
+    problems
+        .into_iter()
+        .map(|x| DependencyProblemReport {
+            message: x.describe(analyses),
+            problem: x,
+        })
+        .collect()
+}
+
+///////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////
+
+#[get("/validateanalyses")]
+fn validate_analyses(_user: UserWithRoles) -> Option<Json<Vec<DependencyProblemReport>>> {
+    let analyses = get_list_of_analyses()?;
+    let problems = get_dependency_problems(&analyses);
+    Some(Json(get_dependency_problem_reports(problems, &analyses)))
+}
+
+// The problems involving the analysis if it were saved like this. /updateanalysis refuses it if
+// any of them are new and block saving, and otherwise reports them with the saved analysis
+#[post("/validateanalysis", format = "application/json", data = "<ap>")]
+fn validate_analysis(
+    _user: UserWithRoles,
+    ap: Json<AnalysisPackage>,
+) -> Option<Json<Vec<DependencyProblemReport>>> {
+    get_metadata_from_analysis_id(&ap.id)?;
+    Some(Json(get_problems_involving(&ap.id, &ap.metadata)?))
+}
+
+pub fn validation_routes() -> Vec<rocket::Route> {
+    routes![validate_analyses, validate_analysis]
+}